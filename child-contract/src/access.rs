@@ -0,0 +1,62 @@
+/// Role management, delegated to the shared `farm_roles` crate. "Admin" in
+/// method docs means the owner or any account holding the `FarmManager` role.
+/// `Upgrader` may replace the contract code; the farms take no fees, so
+/// `FeeManager` is not granted here.
+use crate::*;
+use farm_roles::Ownable;
+use near_sdk::GasWeight;
+
+const GAS_FOR_MIGRATE: Gas = Gas::from_tgas(50);
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// **(Owner-only)** Gives `role` to `account_id`.
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        assert!(role != Role::FeeManager, "FeeManager is not used by this contract");
+        self.roles.grant_role(role, account_id);
+    }
+
+    /// **(Owner-only)** Takes `role` away from `account_id`.
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        self.roles.revoke_role(role, account_id);
+    }
+
+    /// The owner holds every role.
+    pub fn has_role(&self, role: Role, account_id: AccountId) -> bool {
+        self.roles.has_role(role, &account_id)
+    }
+
+    /// **(Owner-only)** Starts an ownership transfer; `new_owner` must call `accept_owner`.
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
+        self.roles.set_owner(new_owner);
+    }
+
+    pub fn accept_owner(&mut self) {
+        self.roles.accept_owner();
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.roles.get_owner()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.roles.get_pending_owner()
+    }
+
+    /// **(Upgrader role)** Deploys the contract code passed as the raw call
+    /// input and calls `migrate` on it in the same batch, so code without a
+    /// working `migrate` is rolled back.
+    pub fn upgrade(&self) -> Promise {
+        self.roles.assert_role(Role::Upgrader);
+        let code = env::input().expect("Contract code is required");
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call_weight(
+                "migrate".to_string(),
+                vec![],
+                NearToken::from_yoctonear(0),
+                GAS_FOR_MIGRATE,
+                GasWeight(1),
+            )
+    }
+}
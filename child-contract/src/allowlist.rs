@@ -0,0 +1,81 @@
+/// Merkle-proof gating for private farms.
+/// Leaves are `sha256(account_id)`, or `sha256("<account_id>:<cap>")` when the
+/// snapshot also limits how much that account may stake. Inner nodes hash the
+/// sorted pair of their children, so proofs carry no left/right flags.
+use crate::*;
+
+/// Proof attached to a stake message for a private farm.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AllowlistProof {
+    pub proof: Vec<Base58CryptoHash>,
+    /// Per-account cap committed to in the leaf, if any.
+    #[serde(default)]
+    pub cap: Option<U128>,
+}
+
+pub(crate) fn allowlist_leaf(account_id: &AccountId, cap: Option<u128>) -> CryptoHash {
+    match cap {
+        Some(cap) => env::sha256_array(format!("{}:{}", account_id, cap).as_bytes()),
+        None => env::sha256_array(account_id.as_bytes()),
+    }
+}
+
+pub(crate) fn hash_pair(a: &CryptoHash, b: &CryptoHash) -> CryptoHash {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut buf = Vec::with_capacity(64);
+    buf.extend_from_slice(first);
+    buf.extend_from_slice(second);
+    env::sha256_array(&buf)
+}
+
+impl AllowlistProof {
+    pub(crate) fn verify(&self, root: &CryptoHash, account_id: &AccountId) -> bool {
+        let computed = self
+            .proof
+            .iter()
+            .fold(allowlist_leaf(account_id, self.cap.map(|c| c.0)), |acc, node| {
+                hash_pair(&acc, &CryptoHash::from(*node))
+            });
+        &computed == root
+    }
+}
+
+impl FarmParams {
+    /// Checks `staker` against the farm's allowlist and returns its cap, if any.
+    pub(crate) fn check_allowlist(
+        &self,
+        staker: &AccountId,
+        allowlist: Option<&AllowlistProof>,
+    ) -> Result<Option<u128>, String> {
+        let root = match &self.allowlist_root {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        let allowlist = allowlist.ok_or("Farm is private, an allowlist proof is required")?;
+        if !allowlist.verify(root, staker) {
+            return Err(format!("Account {} is not on the farm's allowlist", staker));
+        }
+        Ok(allowlist.cap.map(|c| c.0))
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// **(Manager or admin)** Rotates the allowlist root. `None` opens the farm to everyone.
+    /// Existing positions are kept; the new root only gates future stakes.
+    pub fn set_allowlist_root(&mut self, farm_id: u64, root: Option<Base58CryptoHash>) {
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        self.assert_manager_or_admin(&farm);
+        farm.allowlist_root = root.map(CryptoHash::from);
+        self.save_farm(farm_id, &farm);
+        env::log_str(
+            format!(
+                "Farm {} allowlist root set to {:?}",
+                farm_id,
+                root.map(|r| String::from(&r))
+            )
+            .as_str(),
+        );
+    }
+}
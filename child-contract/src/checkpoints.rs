@@ -0,0 +1,177 @@
+/// Historical stake balances, so governance can read voting power at a past
+/// timestamp instead of the (flash-stakeable) current balance.
+///
+/// Each history is a `Vector` with one storage entry per checkpoint, so a
+/// change only writes the entry it adds and a lookup reads O(log n) entries.
+/// Entries added by stakes and compounding are charged to the storage deposit
+/// of the staker, or of the sender of a `StakeFor`. The stake that opens a
+/// position also pays `EXIT_RESERVE_BYTES` up front for the exit that will
+/// close it, so that exit needs no deposit and a drained deposit can never
+/// keep principal in the farm. Partial exits are charged to the staker.
+/// Histories outlive their farm: `close_farm` keeps them, and farm ids are
+/// never reused, so a past snapshot reads the same after the farm is gone.
+use crate::*;
+use near_sdk::collections::Vector;
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub timestamp: u64,
+    pub amount: u128,
+}
+
+/// Storage of one checkpoint: record overhead, a 2-byte tag and 32-byte hash
+/// prefix, the index and the value.
+pub const CHECKPOINT_BYTES: u64 = 40 + 2 + 32 + 8 + 8 + 16;
+
+/// Reserved when a position opens for the exit that closes it: its own entry,
+/// the farm total's and, on unbonding farms, the unbond request.
+pub const EXIT_RESERVE_BYTES: u64 = 2 * CHECKPOINT_BYTES + unbonding::UNBOND_ENTRY_BYTES;
+
+/// A fresh vector stored under `tag` and the hash of `key`.
+fn new_vector<T, K: BorshSerialize>(tag: &[u8], key: &K) -> Vector<T> {
+    let mut prefix = tag.to_vec();
+    prefix.extend(env::sha256(&borsh::to_vec(key).unwrap()));
+    Vector::new(prefix)
+}
+
+/// Whether writing now would add an entry rather than update this block's.
+fn appends(history: Option<&Vector<Checkpoint>>) -> bool {
+    let now = env::block_timestamp();
+    match history.filter(|h| !h.is_empty()) {
+        Some(h) => h.get(h.len() - 1).unwrap().timestamp != now,
+        None => true,
+    }
+}
+
+/// Whether nothing is staked as of the latest entry, so a stake opens the position.
+fn is_closed(history: Option<&Vector<Checkpoint>>) -> bool {
+    let last = history.and_then(|h| h.len().checked_sub(1).and_then(|i| h.get(i)));
+    !matches!(last, Some(checkpoint) if checkpoint.amount > 0)
+}
+
+/// Appends `amount` as of now; several changes in one block keep only the last.
+fn write_checkpoint(history: &mut Vector<Checkpoint>, amount: u128) {
+    let now = env::block_timestamp();
+    let len = history.len();
+    if let Some(mut last) = len.checked_sub(1).and_then(|i| history.get(i)) {
+        if last.timestamp == now {
+            last.amount = amount;
+            history.replace(len - 1, &last);
+            return;
+        }
+    }
+    history.push(&Checkpoint { timestamp: now, amount });
+}
+
+/// Value in effect at `timestamp`, or 0 before the first checkpoint.
+fn value_at(history: &Vector<Checkpoint>, timestamp: u64) -> u128 {
+    // Binary search for the first checkpoint after `timestamp`.
+    let (mut lo, mut hi) = (0, history.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if history.get(mid).unwrap().timestamp <= timestamp {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    match lo {
+        0 => 0,
+        idx => history.get(idx - 1).unwrap().amount,
+    }
+}
+
+impl ChildFarmingContract {
+    /// Bytes the next write for this position will add.
+    fn appended_bytes(&self, account_id: &AccountId, farm_id: u64) -> u64 {
+        let own = self.stake_checkpoints.get(&(account_id.clone(), farm_id));
+        let total = self.total_staked_checkpoints.get(&farm_id);
+        (appends(own.as_ref()) as u64 + appends(total.as_ref()) as u64) * CHECKPOINT_BYTES
+    }
+
+    /// Bytes the next `checkpoint_stake` for this position will charge,
+    /// including the exit reserve if it opens the position.
+    pub(crate) fn checkpoint_bytes(&self, account_id: &AccountId, farm_id: u64) -> u64 {
+        let own = self.stake_checkpoints.get(&(account_id.clone(), farm_id));
+        let reserve = if is_closed(own.as_ref()) { EXIT_RESERVE_BYTES } else { 0 };
+        self.appended_bytes(account_id, farm_id) + reserve
+    }
+
+    /// Records the new stake of `account_id` and the farm's total after a
+    /// stake or compound, charging `payer`'s storage deposit.
+    pub(crate) fn checkpoint_stake(
+        &mut self,
+        account_id: &AccountId,
+        farm_id: u64,
+        amount: u128,
+        total_staked: u128,
+        payer: &AccountId,
+    ) {
+        let bytes = self.checkpoint_bytes(account_id, farm_id);
+        if bytes > 0 {
+            self.debit_storage_deposit(payer, bytes as u128 * env::storage_byte_cost().as_yoctonear());
+        }
+        self.write_stake_checkpoint(account_id, farm_id, amount, total_staked);
+    }
+
+    /// Records the stake left after an exit. The exit that closes the
+    /// position is covered by its reserve; any other is charged to the staker.
+    pub(crate) fn checkpoint_exit(
+        &mut self,
+        account_id: &AccountId,
+        farm_id: u64,
+        amount: u128,
+        total_staked: u128,
+    ) {
+        let bytes = self.appended_bytes(account_id, farm_id);
+        if amount > 0 && bytes > 0 {
+            self.debit_storage_deposit(account_id, bytes as u128 * env::storage_byte_cost().as_yoctonear());
+        }
+        self.write_stake_checkpoint(account_id, farm_id, amount, total_staked);
+    }
+
+    /// Writes the checkpoints without any storage charge.
+    pub(crate) fn write_stake_checkpoint(
+        &mut self,
+        account_id: &AccountId,
+        farm_id: u64,
+        amount: u128,
+        total_staked: u128,
+    ) {
+        let key = (account_id.clone(), farm_id);
+        let mut history = self
+            .stake_checkpoints
+            .get(&key)
+            .unwrap_or_else(|| new_vector(b"cp", &key));
+        write_checkpoint(&mut history, amount);
+        self.stake_checkpoints.insert(&key, &history);
+
+        let mut totals = self
+            .total_staked_checkpoints
+            .get(&farm_id)
+            .unwrap_or_else(|| new_vector(b"ct", &farm_id));
+        write_checkpoint(&mut totals, total_staked);
+        self.total_staked_checkpoints.insert(&farm_id, &totals);
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Stake of `account_id` in `farm_id` as of `timestamp_sec`.
+    pub fn get_stake_at(&self, account_id: AccountId, farm_id: u64, timestamp_sec: u64) -> U128 {
+        U128(
+            self.stake_checkpoints
+                .get(&(account_id, farm_id))
+                .map_or(0, |history| value_at(&history, timestamp_sec * 1_000_000_000)),
+        )
+    }
+
+    /// Total staked in `farm_id` as of `timestamp_sec`.
+    pub fn get_total_staked_at(&self, farm_id: u64, timestamp_sec: u64) -> U128 {
+        U128(
+            self.total_staked_checkpoints
+                .get(&farm_id)
+                .map_or(0, |history| value_at(&history, timestamp_sec * 1_000_000_000)),
+        )
+    }
+}
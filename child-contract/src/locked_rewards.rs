@@ -0,0 +1,150 @@
+/// Pays selected reward tokens as vesting `locked_token` instead of liquid tokens.
+/// The reward token must be the locked_token's base token, and this contract
+/// must be one of its minters:
+/// 1. `ft_transfer_call` the reward to the locked_token with `{"type": "Mint"}`,
+///    which mints locked tokens to this contract;
+/// 2. `ft_transfer` the minted amount to the receiver, which the locked_token
+///    turns into a vesting schedule.
+///
+/// Unused rewards from step 1 go back to the staker's `accrued_rewards`; locked
+/// tokens that could not be delivered in step 2 wait in `pending_payouts`.
+use crate::*;
+use near_sdk::{GasWeight, PromiseResult};
+
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas::from_tgas(40);
+/// The transfer of the minted tokens and its `on_payout`, plus its own work.
+const GAS_FOR_ON_LOCKED_MINT: Gas = Gas::from_tgas(20);
+
+impl ChildFarmingContract {
+    /// Starts the two-step payout of `amount` of reward token `index` as locked tokens.
+    pub(crate) fn internal_lock_reward(
+        &mut self,
+        farm: &FarmParams,
+        farm_id: u64,
+        index: usize,
+        staker: &AccountId,
+        receiver: &AccountId,
+        amount: u128,
+    ) {
+        let locked_token = farm.reward_vesting[index].clone().expect("Reward is not vested");
+        let msg = near_sdk::serde_json::json!({
+            "type": "Mint",
+            "account_id": env::current_account_id(),
+        })
+        .to_string();
+        self.add_outstanding(&farm.reward_tokens[index], amount);
+        self.start_claim(farm_id);
+        Promise::new(farm.reward_tokens[index].clone())
+            .function_call_weight(
+                "ft_transfer_call".to_string(),
+                near_sdk::serde_json::to_vec(&serde_json::json!({
+                    "receiver_id": locked_token,
+                    "amount": U128(amount),
+                    "msg": msg,
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1),
+                GAS_FOR_FT_TRANSFER_CALL,
+                GasWeight(1),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_LOCKED_MINT)
+                    .with_unused_gas_weight(1)
+                    .on_locked_mint(
+                        farm_id,
+                        index as u32,
+                        staker.clone(),
+                        receiver.clone(),
+                        locked_token,
+                        U128(amount),
+                    ),
+            );
+    }
+
+    /// Credits `amount` of reward token `index` back to a stake. A stake that
+    /// was dropped meanwhile is recreated without principal, just to hold it.
+    pub(crate) fn restore_accrued(
+        &mut self,
+        account_id: &AccountId,
+        farm_id: u64,
+        index: usize,
+        amount: u128,
+    ) {
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        farm.unclaimed_reward[index] = farm.unclaimed_reward[index].saturating_add(amount);
+        farm.accrued_reward[index] = farm.accrued_reward[index].saturating_add(amount);
+        self.save_farm(farm_id, &farm);
+        let stake_key = (account_id.clone(), farm_id);
+        let mut stake_info = self.stakes.get(&stake_key).unwrap_or_else(|| StakeInfo {
+            amount: 0,
+            lockup_end: env::block_timestamp(),
+            reward_debt: farm.reward_per_share.clone(),
+            accrued_rewards: vec![0; farm.reward_tokens.len()],
+            beneficiary: None,
+            operator: None,
+            tranches: vec![],
+            balances: vec![0; farm.staking_weights.len()],
+            allowlist_cap: None,
+        });
+        stake_info.accrued_rewards[index] = stake_info.accrued_rewards[index].saturating_add(amount);
+        self.save_stake(&stake_key, &stake_info);
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Private callback after the reward was sent to the locked_token.
+    /// `ft_transfer_call` resolves to the amount the locked_token kept.
+    #[private]
+    pub fn on_locked_mint(
+        &mut self,
+        farm_id: u64,
+        index: u32,
+        staker: AccountId,
+        receiver: AccountId,
+        locked_token: AccountId,
+        amount: U128,
+    ) {
+        self.end_claim(farm_id);
+        let index = index as usize;
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map(|v| v.0.min(amount.0))
+                .unwrap_or(0),
+            _ => 0,
+        };
+        let unused = amount.0 - used;
+        let farm = self.farms.get(&farm_id).expect("Farm not found");
+        self.sub_outstanding(&farm.reward_tokens[index], amount.0);
+        if unused > 0 {
+            self.restore_accrued(&staker, farm_id, index, unused);
+            env::log_str(
+                format!(
+                    "Locking {} rewards of farm {} failed, restored to {}",
+                    unused, farm_id, staker
+                )
+                .as_str(),
+            );
+        }
+        if used > 0 {
+            self.internal_payout(locked_token, receiver, used);
+        }
+    }
+
+    /// **(Manager or admin)** Routes reward token `reward_token` through the
+    /// `locked_token` contract, or back to liquid payouts with `None`. Queued
+    /// with the minimum notice, like `queue_farm_change`; returns the change id.
+    pub fn set_reward_vesting(
+        &mut self,
+        farm_id: u64,
+        reward_token: AccountId,
+        locked_token: Option<AccountId>,
+    ) -> u64 {
+        self.queue_farm_change(
+            farm_id,
+            FarmParamChange::RewardVesting { reward_token, locked_token },
+            None,
+        )
+    }
+}
@@ -0,0 +1,130 @@
+/// Optional, display-only farm metadata, kept apart from `FarmParams` so the
+/// hot path never loads it. Its storage is measured and debited from the
+/// manager's storage deposit; the bytes are tracked in `FarmParams::storage_used`.
+use crate::*;
+use near_sdk::json_types::Base64VecU8;
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_DESCRIPTION_LEN: usize = 1024;
+/// Leaves room for small SVG data URIs.
+const MAX_ICON_LEN: usize = 4096;
+const MAX_LINK_LEN: usize = 256;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FarmMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// URL or data URI.
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Project website.
+    #[serde(default)]
+    pub link: Option<String>,
+    /// URL to an off-chain JSON file with more info.
+    #[serde(default)]
+    pub reference: Option<String>,
+    /// Base64-encoded sha256 hash of the JSON file at `reference`, as in NEP-148.
+    #[serde(default)]
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+fn assert_max_len(field: &str, value: Option<&String>, max: usize) {
+    if let Some(value) = value {
+        assert!(value.len() <= max, "Metadata {} is longer than {} bytes", field, max);
+    }
+}
+
+impl FarmMetadata {
+    fn assert_valid(&self) {
+        assert!(!self.name.is_empty(), "Metadata name must not be empty");
+        assert_max_len("name", Some(&self.name), MAX_NAME_LEN);
+        assert_max_len("description", self.description.as_ref(), MAX_DESCRIPTION_LEN);
+        assert_max_len("icon", self.icon.as_ref(), MAX_ICON_LEN);
+        assert_max_len("link", self.link.as_ref(), MAX_LINK_LEN);
+        assert_max_len("reference", self.reference.as_ref(), MAX_LINK_LEN);
+        assert_eq!(
+            self.reference.is_some(),
+            self.reference_hash.is_some(),
+            "reference and reference_hash must be set together"
+        );
+        if let Some(hash) = &self.reference_hash {
+            assert_eq!(hash.0.len(), 32, "reference_hash must be 32 bytes");
+        }
+    }
+}
+
+impl ChildFarmingContract {
+    /// Writes (or removes with `None`) the metadata of `farm_id` and settles the
+    /// storage difference with the farm manager's storage deposit. The caller
+    /// persists `farm`.
+    pub(crate) fn internal_set_metadata(
+        &mut self,
+        farm_id: u64,
+        farm: &mut FarmParams,
+        metadata: Option<FarmMetadata>,
+    ) {
+        let initial_storage = env::storage_usage();
+        match &metadata {
+            Some(metadata) => {
+                metadata.assert_valid();
+                self.farm_metadata.insert(&farm_id, metadata);
+            }
+            None => {
+                self.farm_metadata.remove(&farm_id);
+            }
+        }
+        let final_storage = env::storage_usage();
+
+        let byte_cost = env::storage_byte_cost().as_yoctonear();
+        let deposit = self.storage_deposits.get(&farm.manager).unwrap_or(0);
+        if final_storage > initial_storage {
+            let added = final_storage - initial_storage;
+            let cost = added as u128 * byte_cost;
+            if deposit < cost {
+                env::panic_str(&format!(
+                    "Insufficient storage. Need {} more yoctoNEAR.",
+                    cost - deposit
+                ));
+            }
+            self.storage_deposits.insert(&farm.manager, &(deposit - cost));
+            farm.storage_used += added;
+        } else {
+            let freed = (initial_storage - final_storage).min(farm.storage_used);
+            self.storage_deposits
+                .insert(&farm.manager, &(deposit + freed as u128 * byte_cost));
+            farm.storage_used -= freed;
+        }
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// **(Manager or admin)** Replaces the farm's metadata, or clears it with `None`.
+    /// The manager's storage balance pays for added bytes and is refunded for
+    /// freed ones. Only the manager may attach a deposit, which is added to that
+    /// balance first; an admin's deposit would otherwise end up as the manager's.
+    #[payable]
+    pub fn update_farm_metadata(&mut self, farm_id: u64, metadata: Option<FarmMetadata>) {
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        self.assert_manager_or_admin(&farm);
+        let attached = env::attached_deposit().as_yoctonear();
+        if attached > 0 {
+            assert_eq!(
+                env::predecessor_account_id(),
+                farm.manager,
+                "Only the farm manager can attach a deposit"
+            );
+            let current = self.storage_deposits.get(&farm.manager).unwrap_or(0);
+            self.storage_deposits.insert(&farm.manager, &(current + attached));
+        }
+        self.internal_set_metadata(farm_id, &mut farm, metadata);
+        self.save_farm(farm_id, &farm);
+        env::log_str(format!("Farm {} metadata updated", farm_id).as_str());
+    }
+
+    pub fn get_farm_metadata(&self, farm_id: u64) -> Option<FarmMetadata> {
+        self.farm_metadata.get(&farm_id)
+    }
+}
@@ -0,0 +1,232 @@
+/// Upgrade path from the first release of this contract, whose farms and
+/// stakes predate every field added since. `migrate` only sets the legacy maps
+/// aside; `migrate_batch` then moves a bounded number of entries per call, farms
+/// first, into maps under new prefixes:
+/// - farms become single-token `Session` farms managed by the old admin, and
+///   `Ended` (out of rewards) becomes `Exhausted`;
+/// - rewards pending on each stake are settled into its `accrued_rewards`,
+///   which seeds the farm's `unclaimed_reward` and the per-token obligations;
+/// - a running lockup becomes a tranche, and staked positions get their first
+///   checkpoint.
+use crate::*;
+use crate::multi_asset::WEIGHT_DENOMINATOR;
+use farm_roles::Ownable;
+
+/// Storage key of the first-release maps that `migrate_batch` has yet to empty.
+const LEGACY_KEY: &[u8] = b"legacy";
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum OldFarmStatus {
+    Active,
+    Ended,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldFarmParams {
+    pub staking_token: AccountId,
+    pub reward_tokens: Vec<AccountId>,
+    pub reward_per_session: Vec<u128>,
+    pub session_interval: u64,
+    pub start_time: u64,
+    pub last_distribution: u64,
+    pub total_staked: u128,
+    pub reward_per_share: Vec<u128>,
+    pub lockup_period: u64,
+    pub remaining_reward: Vec<u128>,
+    pub status: OldFarmStatus,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldStakeInfo {
+    pub amount: u128,
+    pub lockup_end: u64,
+    pub reward_debt: Vec<u128>,
+    pub accrued_rewards: Vec<u128>,
+}
+
+/// Contract state as first released.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldChildFarmingContract {
+    pub farms: UnorderedMap<u64, OldFarmParams>,
+    pub stakes: UnorderedMap<(AccountId, u64), OldStakeInfo>,
+    pub farm_count: u64,
+    pub storage_deposits: UnorderedMap<AccountId, Balance>,
+    pub admin: AccountId,
+}
+
+/// First-release entries still to be migrated.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyState {
+    pub farms: UnorderedMap<u64, OldFarmParams>,
+    pub stakes: UnorderedMap<(AccountId, u64), OldStakeInfo>,
+    pub admin: AccountId,
+}
+
+/// The last key of `map`, which it can remove without moving another entry.
+fn last_key<K, V>(map: &UnorderedMap<K, V>) -> Option<K>
+where
+    K: BorshSerialize + BorshDeserialize,
+    V: BorshSerialize + BorshDeserialize,
+{
+    map.len().checked_sub(1).and_then(|i| map.keys_as_vector().get(i))
+}
+
+impl OldFarmParams {
+    fn migrate(self, manager: &AccountId, now: u64) -> FarmParams {
+        let num_rewards = self.reward_tokens.len();
+        let status = match self.status {
+            OldFarmStatus::Ended => FarmStatus::Exhausted,
+            OldFarmStatus::Active if now < self.start_time => FarmStatus::Pending,
+            OldFarmStatus::Active => FarmStatus::Active,
+        };
+        FarmParams {
+            staking_token: self.staking_token,
+            reward_tokens: self.reward_tokens,
+            reward_per_session: self.reward_per_session,
+            session_interval: self.session_interval,
+            start_time: self.start_time,
+            last_distribution: self.last_distribution,
+            total_staked: self.total_staked,
+            reward_per_share: self.reward_per_share,
+            lockup_period: self.lockup_period,
+            remaining_reward: self.remaining_reward,
+            status,
+            emission_mode: EmissionMode::Session,
+            manager: manager.clone(),
+            min_stake: 0,
+            max_stake_per_user: None,
+            max_total_staked: None,
+            allowlist_root: None,
+            unbonding_period: 0,
+            total_unbonding: vec![0],
+            extra_staking_tokens: vec![],
+            staking_weights: vec![WEIGHT_DENOMINATOR],
+            total_deposited: vec![self.total_staked],
+            reward_vesting: vec![None; num_rewards],
+            reward_x_token: vec![None; num_rewards],
+            // The first release checked the creator's storage deposit but never debited it.
+            storage_used: 0,
+            pending_changes: vec![],
+            next_change_id: 0,
+            unclaimed_reward: vec![0; num_rewards],
+            reward_dust: vec![0; num_rewards],
+            accrued_reward: vec![0; num_rewards],
+        }
+    }
+}
+
+impl OldStakeInfo {
+    fn migrate(self, now: u64) -> StakeInfo {
+        let tranches = if self.lockup_end > now && self.amount > 0 {
+            vec![LockTranche {
+                token_index: 0,
+                amount: self.amount,
+                unlock_at: self.lockup_end,
+            }]
+        } else {
+            vec![]
+        };
+        StakeInfo {
+            amount: self.amount,
+            lockup_end: self.lockup_end,
+            reward_debt: self.reward_debt,
+            accrued_rewards: self.accrued_rewards,
+            beneficiary: None,
+            operator: None,
+            tranches,
+            balances: vec![self.amount],
+            allowlist_cap: None,
+        }
+    }
+}
+
+impl ChildFarmingContract {
+    /// Panics until `migrate_batch` has moved every first-release entry, so no
+    /// farm distributes before all of its stakes have settled.
+    pub(crate) fn assert_not_migrating(&self) {
+        assert!(
+            !env::storage_has_key(LEGACY_KEY),
+            "Migration in progress, call migrate_batch"
+        );
+    }
+
+    fn migrate_stake(&mut self, stake_key: (AccountId, u64), old: OldStakeInfo, now: u64) {
+        let farm_id = stake_key.1;
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        let mut stake_info = old.migrate(now);
+        // settle_rewards credits the farm with what is pending; what the stake
+        // had accrued before is credited here.
+        for (credited, &accrued) in farm.accrued_reward.iter_mut().zip(&stake_info.accrued_rewards) {
+            *credited = credited.saturating_add(accrued);
+        }
+        self.settle_rewards(&mut farm, &mut stake_info);
+        for (unclaimed, &accrued) in farm.unclaimed_reward.iter_mut().zip(&stake_info.accrued_rewards) {
+            *unclaimed = unclaimed.saturating_add(accrued);
+        }
+        if stake_info.amount > 0 {
+            self.write_stake_checkpoint(&stake_key.0, farm_id, stake_info.amount, farm.total_staked);
+        }
+        self.save_stake(&stake_key, &stake_info);
+        self.save_farm(farm_id, &farm);
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Starts the upgrade from the first release. That release has no
+    /// `upgrade`, so the account deploys this code and calls `migrate` in one
+    /// batch transaction; the owner then calls `migrate_batch` until it returns 0.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let OldChildFarmingContract {
+            farms,
+            stakes,
+            farm_count,
+            storage_deposits,
+            admin,
+        } = env::state_read().expect("No state to migrate");
+        let legacy = LegacyState { farms, stakes, admin: admin.clone() };
+        let remaining = legacy.farms.len() + legacy.stakes.len();
+        env::storage_write(LEGACY_KEY, &borsh::to_vec(&legacy).unwrap());
+
+        let mut this = Self::empty(admin);
+        // The legacy maps keep the old prefixes until migrate_batch empties them.
+        this.farms = UnorderedMap::new(b"farms_v2".to_vec());
+        this.stakes = UnorderedMap::new(b"stakes_v2".to_vec());
+        this.farm_count = farm_count;
+        this.storage_deposits = storage_deposits;
+        env::log_str(format!("Migration started, {} farms and stakes to move", remaining).as_str());
+        this
+    }
+
+    /// **(Owner only)** Moves up to `limit` first-release farms, then stakes,
+    /// to the current layout. Returns how many are left.
+    pub fn migrate_batch(&mut self, limit: u64) -> u64 {
+        self.roles.assert_owner();
+        let mut legacy: LegacyState = env::storage_read(LEGACY_KEY)
+            .map(|bytes| LegacyState::try_from_slice(&bytes).unwrap())
+            .expect("Nothing to migrate");
+        let now = env::block_timestamp();
+        for _ in 0..limit {
+            if let Some(farm_id) = last_key(&legacy.farms) {
+                let old = legacy.farms.remove(&farm_id).unwrap();
+                self.save_farm(farm_id, &old.migrate(&legacy.admin, now));
+            } else if let Some(stake_key) = last_key(&legacy.stakes) {
+                let old = legacy.stakes.remove(&stake_key).unwrap();
+                self.migrate_stake(stake_key, old, now);
+            } else {
+                break;
+            }
+        }
+
+        let remaining = legacy.farms.len() + legacy.stakes.len();
+        if remaining == 0 {
+            env::storage_remove(LEGACY_KEY);
+            env::log_str("Migration finished");
+        } else {
+            env::storage_write(LEGACY_KEY, &borsh::to_vec(&legacy).unwrap());
+        }
+        remaining
+    }
+}
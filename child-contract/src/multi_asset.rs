@@ -0,0 +1,110 @@
+/// Farms that accept several staking tokens, each with a weight in basis points.
+/// Rewards, caps and checkpoints all use weighted stake ("power"), while raw
+/// balances are kept per token so every exit returns the asset that was deposited.
+use crate::*;
+
+/// Weight of 1x, in basis points.
+pub const WEIGHT_DENOMINATOR: u32 = 10_000;
+/// Primary staking token plus extras.
+pub const MAX_STAKING_TOKENS: usize = 4;
+
+/// An additional staking token accepted by a farm, e.g. xJUMP at 12_000 (1.2x).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingTokenInput {
+    pub token_id: AccountId,
+    pub weight_bps: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingTokenView {
+    pub token_id: AccountId,
+    pub weight_bps: u32,
+    /// Raw amount of this token currently staked in the farm.
+    pub total_deposited: U128,
+    /// Raw amount of this token waiting in unbonding queues.
+    pub total_unbonding: U128,
+}
+
+impl FarmParams {
+    /// Every staking token, the primary one first; indices match `staking_weights`.
+    pub(crate) fn staking_tokens(&self) -> Vec<AccountId> {
+        std::iter::once(self.staking_token.clone())
+            .chain(self.extra_staking_tokens.iter().cloned())
+            .collect()
+    }
+
+    pub(crate) fn staking_token_index(&self, token_id: &AccountId) -> Option<usize> {
+        self.staking_tokens().iter().position(|t| t == token_id)
+    }
+
+    /// Resolves an optional token argument, defaulting to the primary staking token.
+    pub(crate) fn expect_staking_token_index(&self, token_id: Option<&AccountId>) -> usize {
+        match token_id {
+            Some(token_id) => self
+                .staking_token_index(token_id)
+                .expect("Not a staking token of this farm"),
+            None => 0,
+        }
+    }
+
+    /// Weighted stake of `raw` units of staking token `index`.
+    pub(crate) fn weighted(&self, index: usize, raw: u128) -> u128 {
+        raw.saturating_mul(self.staking_weights[index] as u128) / WEIGHT_DENOMINATOR as u128
+    }
+
+    /// Largest raw amount of token `index` whose weighted stake fits in `power`.
+    pub(crate) fn raw_for_power(&self, index: usize, power: u128) -> u128 {
+        if power == u128::MAX {
+            return u128::MAX;
+        }
+        power.saturating_mul(WEIGHT_DENOMINATOR as u128) / self.staking_weights[index] as u128
+    }
+}
+
+impl StakeInfo {
+    /// Recomputes `amount` from the raw balances and returns the change in power.
+    /// Recomputing instead of adding deltas keeps rounding from drifting.
+    pub(crate) fn reweigh(&mut self, farm: &FarmParams) -> (u128, u128) {
+        let old = self.amount;
+        self.amount = self
+            .balances
+            .iter()
+            .enumerate()
+            .map(|(i, &raw)| farm.weighted(i, raw))
+            .sum();
+        (old, self.amount)
+    }
+}
+
+/// Applies a power change of a single position to the farm total.
+pub(crate) fn apply_power_change(farm: &mut FarmParams, (old, new): (u128, u128)) {
+    farm.total_staked = farm.total_staked.saturating_sub(old).saturating_add(new);
+}
+
+/// Validates the extra staking tokens of a new farm and returns them with all weights.
+pub(crate) fn parse_staking_tokens(
+    primary: &AccountId,
+    primary_weight_bps: Option<u32>,
+    extra: Vec<StakingTokenInput>,
+) -> (Vec<AccountId>, Vec<u32>) {
+    assert!(
+        extra.len() < MAX_STAKING_TOKENS,
+        "A farm accepts at most {} staking tokens",
+        MAX_STAKING_TOKENS
+    );
+    let mut weights = vec![primary_weight_bps.unwrap_or(WEIGHT_DENOMINATOR)];
+    let mut tokens: Vec<AccountId> = Vec::with_capacity(extra.len());
+    for input in extra {
+        assert!(
+            &input.token_id != primary && !tokens.contains(&input.token_id),
+            "Duplicate staking token {}",
+            input.token_id
+        );
+        tokens.push(input.token_id);
+        weights.push(input.weight_bps);
+    }
+    assert!(weights.iter().all(|&w| w > 0), "Staking weights must be greater than 0");
+    (tokens, weights)
+}
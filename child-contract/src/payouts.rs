@@ -0,0 +1,132 @@
+/// Outbound token transfers. Every `ft_transfer` the contract makes to a user
+/// (rewards, withdrawals, reward refunds, locked tokens) goes through
+/// `internal_payout`; if it fails, the amount is owed to the receiver in
+/// `pending_payouts` until they call `retry_payout`, e.g. after registering
+/// storage with the token.
+use crate::*;
+use near_sdk::PromiseResult;
+
+const GAS_FOR_ON_PAYOUT: Gas = Gas::from_tgas(5);
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingPayoutView {
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+impl ChildFarmingContract {
+    /// Sends `amount` of `token` to `receiver`, recording it in
+    /// `pending_payouts` if the transfer fails.
+    pub(crate) fn internal_payout(&mut self, token: AccountId, receiver: AccountId, amount: u128) {
+        self.internal_payout_from(Promise::new(token.clone()), token, receiver, amount, None);
+    }
+
+    /// Like `internal_payout`, but appends the transfer to `promise`, a batch
+    /// on `token` that may already hold other actions. `storage_charge` is what
+    /// those actions took from an account's storage deposit; if the batch fails,
+    /// it is credited back.
+    pub(crate) fn internal_payout_from(
+        &mut self,
+        promise: Promise,
+        token: AccountId,
+        receiver: AccountId,
+        amount: u128,
+        storage_charge: Option<(AccountId, U128)>,
+    ) {
+        self.add_outstanding(&token, amount);
+        promise
+            .function_call(
+                "ft_transfer".to_string(),
+                near_sdk::serde_json::to_vec(&serde_json::json!({
+                    "receiver_id": receiver,
+                    "amount": U128(amount),
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1),
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_PAYOUT)
+                    .with_unused_gas_weight(1)
+                    .on_payout(token, receiver, U128(amount), storage_charge),
+            );
+    }
+
+    fn add_pending_payout(&mut self, account_id: &AccountId, token: &AccountId, amount: u128) {
+        let mut owed = self.pending_payouts.get(account_id).unwrap_or_default();
+        match owed.iter_mut().find(|(t, _)| t == token) {
+            Some((_, balance)) => *balance = balance.saturating_add(amount),
+            None => owed.push((token.clone(), amount)),
+        }
+        self.pending_payouts.insert(account_id, &owed);
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Private callback after a payout. A failed transfer is kept for the
+    /// receiver, and the attached deposit of a failed batch, refunded to this
+    /// contract, goes back to the storage deposit it was charged to.
+    #[private]
+    pub fn on_payout(
+        &mut self,
+        token: AccountId,
+        receiver: AccountId,
+        amount: U128,
+        storage_charge: Option<(AccountId, U128)>,
+    ) {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            self.sub_outstanding(&token, amount.0);
+            return;
+        }
+        if let Some((payer, charged)) = storage_charge {
+            let deposit = self.storage_deposits.get(&payer).unwrap_or(0);
+            self.storage_deposits.insert(&payer, &(deposit + charged.0));
+        }
+        // Still outstanding, now as a pending payout.
+        self.add_pending_payout(&receiver, &token, amount.0);
+        env::log_str(
+            format!(
+                "Could not send {} of {} to {}; call retry_payout once registered",
+                amount.0, token, receiver
+            )
+            .as_str(),
+        );
+    }
+
+    /// Retries everything of `token` owed to the caller from failed payouts.
+    #[payable]
+    pub fn retry_payout(&mut self, token: AccountId) {
+        near_sdk::assert_one_yocto();
+        let receiver = env::predecessor_account_id();
+        let mut owed = self.pending_payouts.get(&receiver).unwrap_or_default();
+        let pos = owed
+            .iter()
+            .position(|(t, _)| t == &token)
+            .expect("Nothing to retry");
+        let (_, amount) = owed.remove(pos);
+        if owed.is_empty() {
+            self.pending_payouts.remove(&receiver);
+        } else {
+            self.pending_payouts.insert(&receiver, &owed);
+        }
+        // Counted again by `internal_payout`.
+        self.sub_outstanding(&token, amount);
+        self.internal_payout(token, receiver, amount);
+    }
+
+    /// Tokens owed to `account_id` from payouts that failed.
+    pub fn get_pending_payouts(&self, account_id: AccountId) -> Vec<PendingPayoutView> {
+        self.pending_payouts
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, amount)| PendingPayoutView {
+                token_id,
+                amount: U128(amount),
+            })
+            .collect()
+    }
+}
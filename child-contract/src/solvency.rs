@@ -0,0 +1,319 @@
+/// Tripwire for accounting bugs: compares a token's real balance with what the
+/// contract owes in it. For a token, the obligations are:
+/// - across all farms, the raw amount staked and unbonding,
+/// - the undistributed reward pool and its rounding dust,
+/// - rewards distributed to stakers but not paid out yet (`unclaimed_reward`),
+/// - `outstanding_payouts`: transfers in flight and failed payouts still owed.
+///
+/// The farm side is kept per token in `obligations`, adjusted on every farm
+/// write, so the balance callbacks never walk the farms.
+///
+/// Only the balance above the obligations, the surplus, can be recovered, so
+/// stray tokens never come out of user stake or reward pools. A deposit that
+/// `ft_on_transfer` refunds stays in the balance until the token's
+/// `ft_resolve_transfer` runs, so recent refunds are held back as well.
+///
+/// An inbound `ft_transfer_call` is in the balance before `ft_on_transfer` has
+/// recorded it, and nothing here can see it until then, so the surplus may
+/// include a deposit still in flight. Recovery therefore takes an explicit
+/// amount, the stray transfer the owner has identified, and refuses it if it
+/// is more than the surplus.
+use crate::*;
+use farm_roles::Ownable;
+use near_sdk::PromiseResult;
+
+const GAS_FOR_FT_BALANCE_OF: Gas = Gas::from_tgas(5);
+const GAS_FOR_ON_SOLVENCY_BALANCE: Gas = Gas::from_tgas(30);
+const GAS_FOR_ON_RECOVER_BALANCE: Gas = Gas::from_tgas(90);
+
+/// Most farm ids one audit checks for pausing, so the callback's gas stays
+/// bounded however many farms the contract has.
+pub const MAX_PAUSE_PAGE: u64 = 20;
+
+/// Blocks a refund is assumed to stay in the balance before the token's
+/// `ft_resolve_transfer` returns it to the sender.
+pub const REFUND_SETTLE_BLOCKS: u64 = 10;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SolvencyReport {
+    pub token_id: AccountId,
+    pub balance: U128,
+    pub obligations: U128,
+    pub surplus: U128,
+    pub deficit: U128,
+    /// Farms paused because of a deficit.
+    pub paused_farms: Vec<u64>,
+    /// Where the next audit should start to pause the remaining farms, if the
+    /// page did not reach the last farm id.
+    pub next_index: Option<u64>,
+}
+
+impl FarmParams {
+    /// What this farm holds in `token`: staked, unbonding, pooled and unclaimed.
+    pub(crate) fn obligations_in(&self, token: &AccountId) -> u128 {
+        let mut total: u128 = 0;
+        if let Some(i) = self.staking_token_index(token) {
+            total = total
+                .saturating_add(self.total_deposited[i])
+                .saturating_add(self.total_unbonding[i]);
+        }
+        for (j, reward_token) in self.reward_tokens.iter().enumerate() {
+            if reward_token == token {
+                total = total
+                    .saturating_add(self.remaining_reward[j])
+                    .saturating_add(self.reward_dust[j])
+                    .saturating_add(self.unclaimed_reward[j]);
+            }
+        }
+        total
+    }
+}
+
+impl ChildFarmingContract {
+    /// Persists `farm` and moves `obligations` by what it holds now.
+    pub(crate) fn save_farm(&mut self, farm_id: u64, farm: &FarmParams) {
+        let old = self.farms.insert(&farm_id, farm);
+        self.update_obligations(old.as_ref(), Some(farm));
+    }
+
+    pub(crate) fn remove_farm(&mut self, farm_id: u64) {
+        let old = self.farms.remove(&farm_id);
+        self.update_obligations(old.as_ref(), None);
+    }
+
+    fn update_obligations(&mut self, old: Option<&FarmParams>, new: Option<&FarmParams>) {
+        let mut tokens: Vec<AccountId> = vec![];
+        for farm in old.iter().chain(new.iter()) {
+            for token in farm.staking_tokens().iter().chain(farm.reward_tokens.iter()) {
+                if !tokens.contains(token) {
+                    tokens.push(token.clone());
+                }
+            }
+        }
+        for token in tokens {
+            let before = old.map_or(0, |farm| farm.obligations_in(&token));
+            let after = new.map_or(0, |farm| farm.obligations_in(&token));
+            if before == after {
+                continue;
+            }
+            let total = self.obligations.get(&token).unwrap_or(0);
+            let total = total.saturating_add(after).saturating_sub(before);
+            if total == 0 {
+                self.obligations.remove(&token);
+            } else {
+                self.obligations.insert(&token, &total);
+            }
+        }
+    }
+
+    /// Records `amount` of `token` as leaving the contract.
+    pub(crate) fn add_outstanding(&mut self, token: &AccountId, amount: u128) {
+        let current = self.outstanding_payouts.get(token).unwrap_or(0);
+        self.outstanding_payouts.insert(token, &current.saturating_add(amount));
+    }
+
+    /// Records that `amount` of `token` left the contract or was credited back.
+    pub(crate) fn sub_outstanding(&mut self, token: &AccountId, amount: u128) {
+        let settled = self.settled_payouts.get(token).unwrap_or(0);
+        self.settled_payouts.insert(token, &settled.saturating_add(amount));
+        let current = self.outstanding_payouts.get(token).unwrap_or(0);
+        let left = current.saturating_sub(amount);
+        if left == 0 {
+            self.outstanding_payouts.remove(token);
+        } else {
+            self.outstanding_payouts.insert(token, &left);
+        }
+    }
+
+    /// Records `amount` of `token` refunded by `ft_on_transfer`. Only tokens
+    /// the contract uses are tracked, so unknown tokens add no storage.
+    pub(crate) fn record_refund(&mut self, token: &AccountId, amount: u128) {
+        if amount == 0
+            || (!self.registered_tokens.contains(token) && self.internal_obligations(token) == 0)
+        {
+            return;
+        }
+        let in_flight = self.refunds_in_flight(token);
+        self.recent_refunds
+            .insert(token, &(env::block_height(), in_flight.saturating_add(amount)));
+    }
+
+    /// Refunds of `token` that may not have left the contract yet.
+    pub(crate) fn refunds_in_flight(&self, token: &AccountId) -> u128 {
+        match self.recent_refunds.get(token) {
+            Some((height, amount)) if env::block_height() <= height + REFUND_SETTLE_BLOCKS => amount,
+            _ => 0,
+        }
+    }
+
+    /// Everything the contract owes in `token` right now.
+    pub(crate) fn internal_obligations(&self, token: &AccountId) -> u128 {
+        self.outstanding_payouts
+            .get(token)
+            .unwrap_or(0)
+            .saturating_add(self.obligations.get(token).unwrap_or(0))
+    }
+
+    /// Queries this contract's own balance of `token`.
+    pub(crate) fn query_own_balance(token: &AccountId) -> Promise {
+        Promise::new(token.clone()).function_call(
+            "ft_balance_of".to_string(),
+            near_sdk::serde_json::to_vec(&serde_json::json!({
+                "account_id": env::current_account_id(),
+            }))
+            .unwrap(),
+            NearToken::from_yoctonear(0),
+            GAS_FOR_FT_BALANCE_OF,
+        )
+    }
+
+    /// Reads the `ft_balance_of` result of the single promise this callback waits on.
+    pub(crate) fn balance_result() -> u128 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map(|v| v.0)
+                .unwrap_or_else(|_| env::panic_str("Invalid ft_balance_of result")),
+            _ => env::panic_str("ft_balance_of failed"),
+        }
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Compares the contract's balance of `token` with its obligations and logs
+    /// a `solvency_audit` event. Anyone may call it; with `pause_on_deficit` a
+    /// caller holding the `Pauser` role also pauses the pending and active
+    /// farms that use `token` if a deficit is found. Pausing looks at up to
+    /// `limit` farm ids (at most `MAX_PAUSE_PAGE`) from `from_index`; the
+    /// report's `next_index` is where to continue.
+    pub fn audit_solvency(
+        &mut self,
+        token: AccountId,
+        pause_on_deficit: Option<bool>,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Promise {
+        let pause = pause_on_deficit.unwrap_or(false);
+        if pause {
+            self.roles.assert_role(Role::Pauser);
+        }
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(MAX_PAUSE_PAGE).min(MAX_PAUSE_PAGE);
+        Self::query_own_balance(&token).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_SOLVENCY_BALANCE)
+                .with_unused_gas_weight(1)
+                .on_solvency_balance(token, pause, from_index, limit),
+        )
+    }
+
+    /// Private callback with the contract's `ft_balance_of` for `token`.
+    #[private]
+    pub fn on_solvency_balance(
+        &mut self,
+        token: AccountId,
+        pause: bool,
+        from_index: u64,
+        limit: u64,
+    ) -> SolvencyReport {
+        let balance = Self::balance_result();
+        let obligations = self.internal_obligations(&token);
+        let deficit = obligations.saturating_sub(balance);
+
+        let mut paused_farms = vec![];
+        let mut next_index = None;
+        if pause && deficit > 0 {
+            let end = from_index.saturating_add(limit).min(self.farm_count);
+            for farm_id in from_index..end {
+                let mut farm = match self.farms.get(&farm_id) {
+                    Some(farm) => farm,
+                    None => continue,
+                };
+                if farm.staking_token_index(&token).is_none() && !farm.reward_tokens.contains(&token) {
+                    continue;
+                }
+                if farm.status != FarmStatus::Pending && farm.status != FarmStatus::Active {
+                    continue;
+                }
+                farm.distribute(env::block_timestamp());
+                // Distribution may have exhausted it.
+                if farm.status == FarmStatus::Pending || farm.status == FarmStatus::Active {
+                    farm.status = FarmStatus::Paused;
+                    paused_farms.push(farm_id);
+                }
+                self.save_farm(farm_id, &farm);
+            }
+            if end < self.farm_count {
+                next_index = Some(end);
+            }
+        }
+
+        let report = SolvencyReport {
+            token_id: token,
+            balance: U128(balance),
+            obligations: U128(obligations),
+            surplus: U128(balance.saturating_sub(obligations)),
+            deficit: U128(deficit),
+            paused_farms,
+            next_index,
+        };
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            near_sdk::serde_json::json!({
+                "standard": "single-token-farm",
+                "version": "1.0.0",
+                "event": "solvency_audit",
+                "data": [report],
+            })
+        ));
+        report
+    }
+
+    /// **(Owner-only)** Sends `amount` of `token` to `receiver`, as long as the
+    /// contract holds at least that much above its obligations. Requires 1 yoctoNEAR.
+    #[payable]
+    pub fn recover_surplus(&mut self, token: AccountId, receiver: AccountId, amount: U128) -> Promise {
+        near_sdk::assert_one_yocto();
+        self.roles.assert_owner();
+        assert!(amount.0 > 0, "Amount must be greater than 0");
+        let settled = self.settled_payouts.get(&token).unwrap_or(0);
+        Self::query_own_balance(&token).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_RECOVER_BALANCE)
+                .on_recover_balance(token, receiver, amount, U128(settled)),
+        )
+    }
+
+    /// Private callback with the contract's `ft_balance_of` for `token`.
+    /// Payouts that settled while waiting, and recent refunds, may or may not
+    /// be in that balance, so they are subtracted from the surplus as well.
+    #[private]
+    pub fn on_recover_balance(
+        &mut self,
+        token: AccountId,
+        receiver: AccountId,
+        amount: U128,
+        settled: U128,
+    ) -> U128 {
+        let balance = Self::balance_result();
+        let settled_since = self
+            .settled_payouts
+            .get(&token)
+            .unwrap_or(0)
+            .saturating_sub(settled.0);
+        let surplus = balance
+            .saturating_sub(self.internal_obligations(&token))
+            .saturating_sub(settled_since)
+            .saturating_sub(self.refunds_in_flight(&token));
+        if amount.0 > surplus {
+            env::log_str(
+                format!("Only {} surplus of {}, not recovering {}", surplus, token, amount.0).as_str(),
+            );
+            return U128(0);
+        }
+        env::log_str(format!("Recovering {} surplus {} to {}", amount.0, token, receiver).as_str());
+        self.internal_payout(token, receiver, amount.0);
+        amount
+    }
+}
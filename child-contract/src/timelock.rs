@@ -0,0 +1,198 @@
+/// Parameter changes queued with advance notice. A change is applied inside
+/// `FarmParams::distribute` at its own `effective_at`, so time before it is
+/// paid at the old parameters and views see exactly what `update_farm` will do.
+use crate::*;
+
+/// Shortest notice a manager can give for a change.
+pub const MIN_CHANGE_NOTICE_SEC: u64 = 24 * 60 * 60;
+/// Bounds the queue so the farm's storage stays predictable.
+pub const MAX_PENDING_CHANGES: usize = 8;
+/// Longest lockup or unbonding period a change may set. Keeps
+/// `block_timestamp() + period` far from overflowing once the change applies.
+pub const MAX_PERIOD_SEC: u64 = 10 * 365 * 24 * 60 * 60;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde", tag = "type")]
+pub enum FarmParamChange {
+    RewardPerSession { reward_per_session: Vec<U128> },
+    LockupPeriod { lockup_period_sec: u64 },
+    MinStake { min_stake: U128 },
+    MaxStakePerUser { max_stake_per_user: Option<U128> },
+    MaxTotalStaked { max_total_staked: Option<U128> },
+    UnbondingPeriod { unbonding_period_sec: u64 },
+    /// Routes `reward_token` through `locked_token`, or back to liquid payouts.
+    RewardVesting { reward_token: AccountId, locked_token: Option<AccountId> },
+    /// Lets `reward_token` be claimed into `x_token`, or removes the route.
+    RewardXToken { reward_token: AccountId, x_token: Option<AccountId> },
+    /// Stops emissions for good; see `end_farm`.
+    End,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingChange {
+    pub id: u64,
+    pub change: FarmParamChange,
+    pub effective_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingChangeView {
+    pub id: u64,
+    pub change: FarmParamChange,
+    pub effective_at_sec: u64,
+}
+
+impl FarmParams {
+    /// `effective_at` of the oldest queued change, if it is due by `current_time`.
+    pub(crate) fn next_change_due(&self, current_time: u64) -> Option<u64> {
+        self.pending_changes
+            .first()
+            .map(|c| c.effective_at)
+            .filter(|&at| at <= current_time)
+    }
+
+    /// Pops the oldest queued change and applies it. Runs inside `distribute`,
+    /// so it must never panic; values were checked when the change was queued.
+    pub(crate) fn apply_next_change(&mut self) {
+        let pending = self.pending_changes.remove(0);
+        match pending.change {
+            FarmParamChange::RewardPerSession { reward_per_session } => {
+                self.reward_per_session = reward_per_session.iter().map(|v| v.0).collect();
+            }
+            FarmParamChange::LockupPeriod { lockup_period_sec } => {
+                self.lockup_period = lockup_period_sec.saturating_mul(1_000_000_000);
+            }
+            FarmParamChange::MinStake { min_stake } => self.min_stake = min_stake.0,
+            FarmParamChange::MaxStakePerUser { max_stake_per_user } => {
+                self.max_stake_per_user = max_stake_per_user.map(|v| v.0);
+            }
+            FarmParamChange::MaxTotalStaked { max_total_staked } => {
+                self.max_total_staked = max_total_staked.map(|v| v.0);
+            }
+            FarmParamChange::UnbondingPeriod { unbonding_period_sec } => {
+                self.unbonding_period = unbonding_period_sec.saturating_mul(1_000_000_000);
+            }
+            FarmParamChange::RewardVesting { reward_token, locked_token } => {
+                let index = self.reward_token_index(&reward_token).unwrap();
+                self.reward_vesting[index] = locked_token;
+            }
+            FarmParamChange::RewardXToken { reward_token, x_token } => {
+                let index = self.reward_token_index(&reward_token).unwrap();
+                self.reward_x_token[index] = x_token;
+            }
+            FarmParamChange::End => self.status = FarmStatus::Closed,
+        }
+    }
+
+    fn reward_token_index(&self, token: &AccountId) -> Option<usize> {
+        self.reward_tokens.iter().position(|t| t == token)
+    }
+
+    fn assert_valid_change(&self, change: &FarmParamChange) {
+        match change {
+            FarmParamChange::RewardPerSession { reward_per_session } => assert_eq!(
+                reward_per_session.len(),
+                self.reward_tokens.len(),
+                "Must provide reward_per_session for each reward token"
+            ),
+            FarmParamChange::RewardVesting { reward_token, .. }
+            | FarmParamChange::RewardXToken { reward_token, .. } => assert!(
+                self.reward_token_index(reward_token).is_some(),
+                "Not a reward token of this farm"
+            ),
+            FarmParamChange::LockupPeriod { lockup_period_sec: period_sec }
+            | FarmParamChange::UnbondingPeriod { unbonding_period_sec: period_sec } => assert!(
+                *period_sec <= MAX_PERIOD_SEC,
+                "Period must be at most {} seconds",
+                MAX_PERIOD_SEC
+            ),
+            _ => {}
+        }
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// **(Manager or admin)** Queues `change` to take effect at `effective_at_sec`,
+    /// which must be at least `MIN_CHANGE_NOTICE_SEC` from now (the default).
+    /// Returns the change id.
+    pub fn queue_farm_change(
+        &mut self,
+        farm_id: u64,
+        change: FarmParamChange,
+        effective_at_sec: Option<u64>,
+    ) -> u64 {
+        self.update_farm(farm_id);
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        self.assert_manager_or_admin(&farm);
+        assert!(farm.status != FarmStatus::Closed, "Farm is closed");
+        assert!(
+            farm.pending_changes.len() < MAX_PENDING_CHANGES,
+            "Too many pending changes"
+        );
+        farm.assert_valid_change(&change);
+
+        let earliest = env::block_timestamp() + MIN_CHANGE_NOTICE_SEC * 1_000_000_000;
+        let effective_at = effective_at_sec
+            .map(|sec| {
+                sec.checked_mul(1_000_000_000)
+                    .expect("effective_at_sec is out of range")
+            })
+            .unwrap_or(earliest);
+        assert!(
+            effective_at >= earliest,
+            "Changes need at least {} seconds of notice",
+            MIN_CHANGE_NOTICE_SEC
+        );
+
+        let id = farm.next_change_id;
+        farm.next_change_id += 1;
+        // Keep the queue ordered by effective time; ties keep queue order.
+        let pos = farm
+            .pending_changes
+            .partition_point(|c| c.effective_at <= effective_at);
+        farm.pending_changes.insert(pos, PendingChange { id, change, effective_at });
+        self.save_farm(farm_id, &farm);
+
+        env::log_str(
+            format!(
+                "Farm {} queued change {} effective at {}",
+                farm_id, id, effective_at
+            )
+            .as_str(),
+        );
+        id
+    }
+
+    /// **(Manager or admin)** Drops a queued change that has not taken effect yet.
+    pub fn cancel_farm_change(&mut self, farm_id: u64, change_id: u64) {
+        self.update_farm(farm_id);
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        self.assert_manager_or_admin(&farm);
+        let pos = farm
+            .pending_changes
+            .iter()
+            .position(|c| c.id == change_id)
+            .expect("No such pending change");
+        farm.pending_changes.remove(pos);
+        self.save_farm(farm_id, &farm);
+        env::log_str(format!("Farm {} cancelled change {}", farm_id, change_id).as_str());
+    }
+
+    /// Changes that have not taken effect yet, in the order they will apply.
+    pub fn get_pending_changes(&self, farm_id: u64) -> Vec<PendingChangeView> {
+        let farm = match self.farms.get(&farm_id) {
+            Some(farm) => self.simulate_update_farm(&farm),
+            None => return vec![],
+        };
+        farm.pending_changes
+            .into_iter()
+            .map(|c| PendingChangeView {
+                id: c.id,
+                change: c.change,
+                effective_at_sec: c.effective_at / 1_000_000_000,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,114 @@
+/// NEP-141 receiver. Routes incoming transfers according to `msg`
+/// and refunds the whole amount instead of panicking when it can't.
+use crate::*;
+
+/// Typed `ft_on_transfer` message, e.g. `{"type": "Stake", "farm_id": 0}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde", tag = "type")]
+pub enum FtOnTransferMsg {
+    Stake {
+        farm_id: u64,
+        /// Required by private farms.
+        #[serde(default)]
+        allowlist: Option<AllowlistProof>,
+    },
+    /// Credits the stake, and its lockup, to `beneficiary` instead of the sender.
+    /// On private farms the proof is checked against the beneficiary.
+    StakeFor {
+        farm_id: u64,
+        beneficiary: AccountId,
+        #[serde(default)]
+        allowlist: Option<AllowlistProof>,
+    },
+    AddReward { farm_id: u64 },
+    /// Stakes locked for `lock_tier` times the farm's lockup period, from 1
+    /// (same as `Stake`) up to `MAX_LOCK_TIER`.
+    StakeWithLockTier {
+        farm_id: u64,
+        lock_tier: u32,
+        #[serde(default)]
+        allowlist: Option<AllowlistProof>,
+    },
+}
+
+impl FtOnTransferMsg {
+    /// Parses the JSON format, falling back to the legacy `STAKE:<id>` / `ADD_REWARD:<id>` strings.
+    pub fn parse(msg: &str) -> Option<Self> {
+        if let Ok(parsed) = near_sdk::serde_json::from_str::<FtOnTransferMsg>(msg) {
+            return Some(parsed);
+        }
+        let parts: Vec<&str> = msg.split(':').collect();
+        if parts.len() < 2 {
+            return None;
+        }
+        let farm_id: u64 = parts[1].parse().ok()?;
+        match parts[0] {
+            MSG_STAKE => Some(FtOnTransferMsg::Stake { farm_id, allowlist: None }),
+            MSG_ADD_REWARD => Some(FtOnTransferMsg::AddReward { farm_id }),
+            _ => None,
+        }
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    #[payable]
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String
+    ) -> PromiseOrValue<U128> {
+        let token_in = env::predecessor_account_id();
+
+        let result = match FtOnTransferMsg::parse(&msg) {
+            Some(FtOnTransferMsg::Stake { farm_id, allowlist }) => self.stake_tokens(
+                farm_id,
+                token_in,
+                amount.0,
+                &sender_id,
+                &sender_id,
+                allowlist.as_ref(),
+                1,
+            ),
+            Some(FtOnTransferMsg::StakeFor { farm_id, beneficiary, allowlist }) => self.stake_tokens(
+                farm_id,
+                token_in,
+                amount.0,
+                &sender_id,
+                &beneficiary,
+                allowlist.as_ref(),
+                1,
+            ),
+            Some(FtOnTransferMsg::AddReward { farm_id }) => self
+                .add_reward(farm_id, token_in, amount.0, &sender_id)
+                .map(|_| 0),
+            Some(FtOnTransferMsg::StakeWithLockTier { farm_id, lock_tier, allowlist }) => self
+                .stake_tokens(
+                    farm_id,
+                    token_in,
+                    amount.0,
+                    &sender_id,
+                    &sender_id,
+                    allowlist.as_ref(),
+                    lock_tier,
+                ),
+            None => Err(format!("Unrecognized msg: {}", msg)),
+        };
+
+        let refund = match result {
+            // Partially accepted deposits hand back the excess.
+            Ok(refund) => refund,
+            Err(err) => {
+                // unusable deposit => we reject by returning the amount
+                env::log_str(
+                    format!("Refunding {} to {}: {}", amount.0, sender_id, err).as_str(),
+                );
+                amount.0
+            }
+        };
+        // The refund stays in our balance until the token resolves the transfer.
+        self.record_refund(&env::predecessor_account_id(), refund);
+        PromiseOrValue::Value(U128(refund))
+    }
+}
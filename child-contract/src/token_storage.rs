@@ -0,0 +1,172 @@
+/// NEP-145 registration on the token contracts this contract pays out of.
+/// `ft_transfer` to an unregistered account fails, so `create_farm` registers
+/// this contract with every staking and reward token (once per token, paid by
+/// the creator), and liquid reward claims check `storage_balance_of` for the
+/// receiver first, registering them from the claimer's storage deposit. A
+/// failed registration batch credits that charge back through `on_payout`.
+use crate::*;
+use near_sdk::PromiseResult;
+
+/// Deposit attached to `storage_deposit` on a token: 125 bytes, the usual
+/// `storage_balance_bounds().min` of NEP-141 tokens.
+pub const FT_STORAGE_DEPOSIT: u128 = 1_250_000_000_000_000_000_000;
+
+const GAS_FOR_STORAGE_DEPOSIT: Gas = Gas::from_tgas(10);
+const GAS_FOR_STORAGE_BALANCE_OF: Gas = Gas::from_tgas(5);
+const GAS_FOR_ON_TOKEN_REGISTERED: Gas = Gas::from_tgas(5);
+/// Registration, the transfer and its `on_payout`, plus its own work.
+const GAS_FOR_ON_REWARD_STORAGE_CHECKED: Gas = Gas::from_tgas(30);
+
+fn storage_deposit_args(account_id: &AccountId) -> Vec<u8> {
+    near_sdk::serde_json::to_vec(&serde_json::json!({
+        "account_id": account_id,
+        "registration_only": true,
+    }))
+    .unwrap()
+}
+
+impl ChildFarmingContract {
+    /// Takes `amount` from `account_id`'s storage deposit, or panics.
+    pub(crate) fn debit_storage_deposit(&mut self, account_id: &AccountId, amount: u128) {
+        let deposit = self.storage_deposits.get(account_id).unwrap_or(0);
+        if deposit < amount {
+            env::panic_str(&format!(
+                "Insufficient storage. Need {} more yoctoNEAR.",
+                amount - deposit
+            ));
+        }
+        self.storage_deposits.insert(account_id, &(deposit - amount));
+    }
+
+    /// Registers this contract with each of `tokens` it is not registered with
+    /// yet. The deposits come out of `payer`'s storage deposit.
+    pub(crate) fn internal_register_tokens(&mut self, payer: &AccountId, tokens: &[AccountId]) {
+        let current = env::current_account_id();
+        for token in tokens {
+            if self.registered_tokens.contains(token) {
+                continue;
+            }
+            self.debit_storage_deposit(payer, FT_STORAGE_DEPOSIT);
+            self.registered_tokens.insert(token);
+            Promise::new(token.clone())
+                .function_call(
+                    "storage_deposit".to_string(),
+                    storage_deposit_args(&current),
+                    NearToken::from_yoctonear(FT_STORAGE_DEPOSIT),
+                    GAS_FOR_STORAGE_DEPOSIT,
+                )
+                .then(
+                    Self::ext(current.clone())
+                        .with_static_gas(GAS_FOR_ON_TOKEN_REGISTERED)
+                        .on_token_registered(token.clone(), payer.clone()),
+                );
+        }
+    }
+
+    /// Pays `amount` of liquid reward token `index` to `receiver`, registering
+    /// the receiver with the token first if needed.
+    pub(crate) fn internal_send_reward(
+        &mut self,
+        farm: &FarmParams,
+        farm_id: u64,
+        index: usize,
+        staker: &AccountId,
+        receiver: &AccountId,
+        amount: u128,
+    ) {
+        self.add_outstanding(&farm.reward_tokens[index], amount);
+        self.start_claim(farm_id);
+        Promise::new(farm.reward_tokens[index].clone())
+            .function_call(
+                "storage_balance_of".to_string(),
+                near_sdk::serde_json::to_vec(&serde_json::json!({ "account_id": receiver })).unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_STORAGE_BALANCE_OF,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_REWARD_STORAGE_CHECKED)
+                    .with_unused_gas_weight(1)
+                    .on_reward_storage_checked(
+                        farm_id,
+                        index as u32,
+                        staker.clone(),
+                        receiver.clone(),
+                        U128(amount),
+                    ),
+            );
+    }
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Private callback after registering this contract with `token`. On
+    /// failure the registration is forgotten and `payer` is refunded.
+    #[private]
+    pub fn on_token_registered(&mut self, token: AccountId, payer: AccountId) {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        self.registered_tokens.remove(&token);
+        let deposit = self.storage_deposits.get(&payer).unwrap_or(0);
+        self.storage_deposits.insert(&payer, &(deposit + FT_STORAGE_DEPOSIT));
+        env::log_str(format!("Could not register with {}, refunded {}", token, payer).as_str());
+    }
+
+    /// Private callback with the receiver's `storage_balance_of` on the reward
+    /// token. Tokens that don't answer are treated as registered; the transfer
+    /// is tried anyway.
+    #[private]
+    pub fn on_reward_storage_checked(
+        &mut self,
+        farm_id: u64,
+        index: u32,
+        staker: AccountId,
+        receiver: AccountId,
+        amount: U128,
+    ) {
+        self.end_claim(farm_id);
+        let index = index as usize;
+        let registered = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<Option<serde_json::Value>>(&value)
+                    .map(|balance| balance.is_some())
+                    .unwrap_or(true)
+            }
+            _ => true,
+        };
+        let farm = self.farms.get(&farm_id).expect("Farm not found");
+        self.sub_outstanding(&farm.reward_tokens[index], amount.0);
+        let mut transfer = Promise::new(farm.reward_tokens[index].clone());
+        let mut storage_charge = None;
+        if !registered {
+            let deposit = self.storage_deposits.get(&staker).unwrap_or(0);
+            if deposit < FT_STORAGE_DEPOSIT {
+                self.restore_accrued(&staker, farm_id, index, amount.0);
+                env::log_str(
+                    format!(
+                        "{} is not registered with {}; add a storage deposit to claim",
+                        receiver, farm.reward_tokens[index]
+                    )
+                    .as_str(),
+                );
+                return;
+            }
+            self.storage_deposits.insert(&staker, &(deposit - FT_STORAGE_DEPOSIT));
+            storage_charge = Some((staker, U128(FT_STORAGE_DEPOSIT)));
+            transfer = transfer.function_call(
+                "storage_deposit".to_string(),
+                storage_deposit_args(&receiver),
+                NearToken::from_yoctonear(FT_STORAGE_DEPOSIT),
+                GAS_FOR_STORAGE_DEPOSIT,
+            );
+        }
+        self.internal_payout_from(
+            transfer,
+            farm.reward_tokens[index].clone(),
+            receiver,
+            amount.0,
+            storage_charge,
+        );
+    }
+}
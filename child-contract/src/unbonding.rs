@@ -0,0 +1,175 @@
+/// Cooldown-based exits for farms created with an `unbonding_period`.
+/// `request_unstake` moves principal out of the farm into an unbonding entry,
+/// which earns nothing; `withdraw_unbonded` pays matured entries out.
+/// `request_unstake` is the only exit from such a farm. A partial request pays
+/// for its entry from the staker's storage deposit; the request that closes the
+/// position draws on the reserve paid when it opened (see `checkpoints`), so a
+/// drained deposit never keeps principal in the farm. `withdraw_unbonded`
+/// credits the freed storage back to the staker.
+use crate::*;
+
+/// Bounds the per-user queue.
+pub const MAX_UNBOND_ENTRIES: usize = 10;
+/// Storage of a queue holding a single entry: record overhead, the map
+/// prefix, the (account, farm) key, the length and the entry.
+pub const UNBOND_ENTRY_BYTES: u64 = 40 + 9 + (4 + 64 + 8) + 4 + (1 + 16 + 8);
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+pub struct UnbondEntry {
+    /// Index into the farm's staking tokens.
+    pub token_index: u8,
+    pub amount: u128,
+    pub unlock_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnbondEntryView {
+    pub token_id: AccountId,
+    pub amount: U128,
+    pub unlock_at_sec: u64,
+}
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Starts the cooldown for `amount` of the caller's stake. The amount stops
+    /// earning immediately and can be withdrawn after the farm's unbonding period.
+    /// `token_id` defaults to the primary staking token.
+    #[payable]
+    pub fn request_unstake(&mut self, farm_id: u64, amount: U128, token_id: Option<AccountId>) {
+        near_sdk::assert_one_yocto();
+        let user = env::predecessor_account_id();
+        let to_unstake = amount.0;
+        assert!(to_unstake > 0, "Amount must be greater than 0");
+
+        let index = self
+            .farms
+            .get(&farm_id)
+            .expect("Farm not found")
+            .expect_staking_token_index(token_id.as_ref());
+        let stake_key = (user.clone(), farm_id);
+        let mut stake_info = self.stakes.get(&stake_key).expect("No stake found");
+
+        stake_info.assert_unlocked(index, to_unstake, env::block_timestamp());
+
+        self.update_farm(farm_id);
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        assert!(
+            farm.unbonding_period > 0,
+            "Farm has no unbonding period, use withdraw"
+        );
+        let mut entries = self.unbonding.get(&stake_key).unwrap_or_default();
+        assert!(
+            entries.len() < MAX_UNBOND_ENTRIES,
+            "Too many pending unbond requests"
+        );
+
+        // Settle pending rewards.
+        self.settle_rewards(&mut farm, &mut stake_info);
+
+        stake_info.balances[index] -= to_unstake;
+        let change = stake_info.reweigh(&farm);
+        assert!(
+            stake_info.amount == 0 || stake_info.amount >= farm.min_stake,
+            "Remaining stake would be below the minimum of {}",
+            farm.min_stake
+        );
+        apply_power_change(&mut farm, change);
+        farm.total_deposited[index] = farm.total_deposited[index].saturating_sub(to_unstake);
+        farm.total_unbonding[index] = farm.total_unbonding[index].saturating_add(to_unstake);
+
+        let unlock_at = env::block_timestamp()
+            .checked_add(farm.unbonding_period)
+            .expect("Unbonding period overflows the unlock time");
+        entries.push(UnbondEntry {
+            token_index: index as u8,
+            amount: to_unstake,
+            unlock_at,
+        });
+
+        self.save_stake(&stake_key, &stake_info);
+        let initial_storage = env::storage_usage();
+        self.unbonding.insert(&stake_key, &entries);
+        let added = env::storage_usage().saturating_sub(initial_storage);
+        if stake_info.amount > 0 {
+            self.debit_storage_deposit(&user, added as u128 * env::storage_byte_cost().as_yoctonear());
+        }
+        self.save_farm(farm_id, &farm);
+        self.checkpoint_exit(&user, farm_id, stake_info.amount, farm.total_staked);
+
+        env::log_str(
+            format!(
+                "User {} started unbonding {} tokens from farm {}, unlocks at {}",
+                user, to_unstake, farm_id, unlock_at
+            )
+            .as_str(),
+        );
+    }
+
+    /// Pays out every matured unbonding entry of the caller in `farm_id` and
+    /// credits the storage they held to the caller's storage deposit.
+    #[payable]
+    pub fn withdraw_unbonded(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let user = env::predecessor_account_id();
+        let stake_key = (user.clone(), farm_id);
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        let entries = self.unbonding.get(&stake_key).unwrap_or_default();
+
+        let now = env::block_timestamp();
+        let (matured, pending): (Vec<UnbondEntry>, Vec<UnbondEntry>) =
+            entries.into_iter().partition(|e| e.unlock_at <= now);
+        assert!(!matured.is_empty(), "Nothing to withdraw");
+        let mut to_withdraw = vec![0_u128; farm.staking_weights.len()];
+        for entry in &matured {
+            to_withdraw[entry.token_index as usize] += entry.amount;
+        }
+
+        let initial_storage = env::storage_usage();
+        if pending.is_empty() {
+            self.unbonding.remove(&stake_key);
+        } else {
+            self.unbonding.insert(&stake_key, &pending);
+        }
+        let freed = initial_storage.saturating_sub(env::storage_usage());
+        if freed > 0 {
+            let deposit = self.storage_deposits.get(&user).unwrap_or(0);
+            self.storage_deposits
+                .insert(&user, &(deposit + freed as u128 * env::storage_byte_cost().as_yoctonear()));
+        }
+        let staking_tokens = farm.staking_tokens();
+        for (i, &amount) in to_withdraw.iter().enumerate() {
+            if amount == 0 {
+                continue;
+            }
+            farm.total_unbonding[i] = farm.total_unbonding[i].saturating_sub(amount);
+            self.internal_payout(staking_tokens[i].clone(), user.clone(), amount);
+            env::log_str(
+                format!(
+                    "User {} withdrew {} unbonded {} from farm {}",
+                    user, amount, staking_tokens[i], farm_id
+                )
+                .as_str(),
+            );
+        }
+        self.save_farm(farm_id, &farm);
+    }
+
+    /// Pending unbond requests of `account_id` in `farm_id`, oldest first.
+    pub fn get_unbonding(&self, account_id: AccountId, farm_id: u64) -> Vec<UnbondEntryView> {
+        let staking_tokens = match self.farms.get(&farm_id) {
+            Some(farm) => farm.staking_tokens(),
+            None => return vec![],
+        };
+        self.unbonding
+            .get(&(account_id, farm_id))
+            .unwrap_or_default()
+            .iter()
+            .map(|e| UnbondEntryView {
+                token_id: staking_tokens[e.token_index as usize].clone(),
+                amount: U128(e.amount),
+                unlock_at_sec: e.unlock_at / 1_000_000_000,
+            })
+            .collect()
+    }
+}
@@ -1,4 +1,6 @@
 use crate::*;
+use crate::metadata::FarmMetadata;
+use crate::multi_asset::StakingTokenView;
 use near_sdk::{
     near_bindgen,
     serde::{Deserialize, Serialize},
@@ -17,8 +19,28 @@ pub struct FarmView {
     pub last_distribution_sec: u64,
     pub total_staked: U128,
     pub reward_per_share: Vec<U128>,
+    /// Per reward token, what rounding left out of the last distribution;
+    /// it goes back into the pool on the next one.
+    pub reward_dust: Vec<U128>,
     pub lockup_period_sec: u64,
     pub status: FarmStatus,
+    pub emission_mode: EmissionMode,
+    pub manager: AccountId,
+    pub min_stake: U128,
+    pub max_stake_per_user: Option<U128>,
+    pub max_total_staked: Option<U128>,
+    pub allowlist_root: Option<Base58CryptoHash>,
+    pub unbonding_period_sec: u64,
+    /// Every accepted staking token with its weight and totals, primary first.
+    pub staking_tokens: Vec<StakingTokenView>,
+    /// `locked_token` contract per reward token, if that reward vests.
+    pub reward_vesting: Vec<Option<AccountId>>,
+    /// `x_token` contract per reward token, if it can be claimed as xToken.
+    pub reward_x_token: Vec<Option<AccountId>>,
+    /// Name, description and links, if the manager set any.
+    pub metadata: Option<FarmMetadata>,
+    /// Stake entries in the farm, including ones that only hold unclaimed rewards.
+    pub stake_count: u64,
 }
 
 impl From<(&FarmParams, u64)> for FarmView {
@@ -47,8 +69,32 @@ impl From<(&FarmParams, u64)> for FarmView {
                 .map(|v| U128(*v))
                 .collect(),
 
+            reward_dust: farm.reward_dust.iter().map(|v| U128(*v)).collect(),
+
             lockup_period_sec: farm.lockup_period / 1_000_000_000,
             status: farm.status.clone(),
+            emission_mode: farm.emission_mode.clone(),
+            manager: farm.manager.clone(),
+            min_stake: U128(farm.min_stake),
+            max_stake_per_user: farm.max_stake_per_user.map(U128),
+            max_total_staked: farm.max_total_staked.map(U128),
+            allowlist_root: farm.allowlist_root.map(Base58CryptoHash::from),
+            unbonding_period_sec: farm.unbonding_period / 1_000_000_000,
+            staking_tokens: farm
+                .staking_tokens()
+                .into_iter()
+                .enumerate()
+                .map(|(i, token_id)| StakingTokenView {
+                    token_id,
+                    weight_bps: farm.staking_weights[i],
+                    total_deposited: U128(farm.total_deposited[i]),
+                    total_unbonding: U128(farm.total_unbonding[i]),
+                })
+                .collect(),
+            reward_vesting: farm.reward_vesting.clone(),
+            reward_x_token: farm.reward_x_token.clone(),
+            metadata: None,
+            stake_count: 0,
         }
     }
 }
@@ -62,25 +108,124 @@ pub struct StakeInfoView {
     pub reward_debt: Vec<U128>,
     pub accrued_rewards: Vec<U128>,
     pub reward_tokens: Vec<AccountId>,
+    pub beneficiary: Option<AccountId>,
+    pub operator: Option<AccountId>,
+    /// Raw balance per staking token, in the farm's `staking_tokens` order.
+    pub balances: Vec<U128>,
+    /// Part of each balance that can be withdrawn right now.
+    pub unlocked_balances: Vec<U128>,
+    /// Deposits that are still locked, oldest first.
+    pub tranches: Vec<LockTrancheView>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockTrancheView {
+    pub token_id: AccountId,
+    pub amount: U128,
+    pub unlock_at_sec: u64,
+}
+
+impl ChildFarmingContract {
+    /// Builds a farm view including its metadata.
+    fn farm_view(&self, farm_id: u64, farm: &FarmParams) -> FarmView {
+        let mut view = FarmView::from((farm, farm_id));
+        view.metadata = self.farm_metadata.get(&farm_id);
+        view.stake_count = self.farm_stake_counts.get(&farm_id).unwrap_or(0);
+        view
+    }
+
+    /// Builds a stake view with rewards projected up to the current block.
+    fn stake_info_view(&self, farm_id: u64, farm: &FarmParams, info: &StakeInfo) -> StakeInfoView {
+        let now = env::block_timestamp();
+        let staking_tokens = farm.staking_tokens();
+        let sim_farm = self.simulate_update_farm(farm);
+        // Compute pending rewards per reward token:
+        let updated_accrued: Vec<U128> = info.accrued_rewards
+            .iter()
+            .enumerate()
+            .map(|(i, &val)| {
+                let pending = info.amount
+                    .saturating_mul(sim_farm.reward_per_share[i].saturating_sub(info.reward_debt[i]))
+                    / ACC_REWARD_MULTIPLIER;
+                U128(val.saturating_add(pending))
+            })
+            .collect();
+        StakeInfoView {
+            farm_id,
+            amount: U128(info.amount),
+            lockup_end_sec: info.lockup_end / 1_000_000_000,
+            reward_debt: info.reward_debt.iter().map(|v| U128(*v)).collect(),
+            accrued_rewards: updated_accrued,
+            reward_tokens: farm.reward_tokens.clone(),
+            beneficiary: info.beneficiary.clone(),
+            operator: info.operator.clone(),
+            balances: info.balances.iter().map(|v| U128(*v)).collect(),
+            unlocked_balances: (0..info.balances.len())
+                .map(|i| U128(info.unlocked_amount(i, now)))
+                .collect(),
+            tranches: info
+                .tranches
+                .iter()
+                .filter(|t| t.unlock_at > now)
+                .map(|t| LockTrancheView {
+                    token_id: staking_tokens[t.token_index as usize].clone(),
+                    amount: U128(t.amount),
+                    unlock_at_sec: t.unlock_at / 1_000_000_000,
+                })
+                .collect(),
+        }
+    }
 }
 
 #[near_bindgen]
 impl ChildFarmingContract {
+    /// Up to `limit` farms with id `from_index` or above. Ids of deleted farms
+    /// are skipped, so a short page means there are no more farms.
     pub fn list_farms(&self, from_index: u64, limit: u64) -> Vec<FarmView> {
         let mut results = Vec::new();
-        let end = std::cmp::min(self.farm_count, from_index + limit);
-        for farm_id in from_index..end {
+        let mut farm_id = from_index;
+        while farm_id < self.farm_count && (results.len() as u64) < limit {
             if let Some(farm) = self.farms.get(&farm_id) {
-                results.push(FarmView::from((&farm, farm_id)));
+                results.push(self.farm_view(farm_id, &farm));
             }
+            farm_id += 1;
         }
         results
     }
 
+    /// Rewards per reward token that a new stake of `amount` (of the primary
+    /// staking token) would earn over the next `duration_sec`, given the current
+    /// stakers, caps, minimum stake, emission schedule and remaining reward pool.
+    /// A stake the farm would refund earns zeros. Allowlists are not checked;
+    /// on a private farm the result assumes the staker is eligible.
+    pub fn simulate_rewards(&self, farm_id: u64, amount: U128, duration_sec: u64) -> Vec<U128> {
+        let farm = self.farms.get(&farm_id).expect("Farm not found");
+        let mut sim = self.simulate_update_farm(&farm);
+        if sim.check_accepts_stakes().is_err() {
+            return vec![U128(0); sim.reward_tokens.len()];
+        }
+        let accepted = amount.0.min(sim.raw_for_power(0, sim.stake_room(0)));
+        let power = sim.weighted(0, accepted);
+        if accepted == 0 || power < sim.min_stake {
+            return vec![U128(0); sim.reward_tokens.len()];
+        }
+        let start_rps = sim.reward_per_share.clone();
+
+        sim.total_staked = sim.total_staked.saturating_add(power);
+        sim.distribute(env::block_timestamp().saturating_add(duration_sec.saturating_mul(1_000_000_000)));
+
+        sim.reward_per_share
+            .iter()
+            .zip(start_rps.iter())
+            .map(|(end, start)| U128(power.saturating_mul(end - start) / ACC_REWARD_MULTIPLIER))
+            .collect()
+    }
+
     pub fn get_farm(&self, farm_id: u64) -> Option<FarmView> {
         self.farms
             .get(&farm_id)
-            .map(|farm| FarmView::from((&farm, farm_id)))
+            .map(|farm| self.farm_view(farm_id, &farm))
     }
 
     pub fn get_stake_info(
@@ -91,26 +236,7 @@ impl ChildFarmingContract {
         let key = (account_id, farm_id);
         if let Some(info) = self.stakes.get(&key) {
             if let Some(farm) = self.farms.get(&farm_id) {
-                let sim_farm = self.simulate_update_farm(&farm);
-                // Compute pending rewards per reward token:
-                let updated_accrued: Vec<U128> = info.accrued_rewards
-                    .iter()
-                    .enumerate()
-                    .map(|(i, &val)| {
-                        let pending = info.amount
-                            .saturating_mul(sim_farm.reward_per_share[i].saturating_sub(info.reward_debt[i]))
-                            / ACC_REWARD_MULTIPLIER;
-                        U128(val.saturating_add(pending))
-                    })
-                    .collect();
-                return Some(StakeInfoView {
-                    farm_id,
-                    amount: U128(info.amount),
-                    lockup_end_sec: info.lockup_end / 1_000_000_000,
-                    reward_debt: info.reward_debt.iter().map(|v| U128(*v)).collect(),
-                    accrued_rewards: updated_accrued,
-                    reward_tokens: farm.reward_tokens.clone(),
-                });
+                return Some(self.stake_info_view(farm_id, &farm, &info));
             }
         }
         None
@@ -134,26 +260,7 @@ impl ChildFarmingContract {
                 }
                 if count < limit {
                     if let Some(farm) = self.farms.get(&farm_id) {
-                        let sim_farm = self.simulate_update_farm(&farm);
-                        let updated_accrued: Vec<U128> = stake_info
-                            .accrued_rewards
-                            .iter()
-                            .enumerate()
-                            .map(|(i, &val)| {
-                                let pending = stake_info.amount
-                                    .saturating_mul(sim_farm.reward_per_share[i].saturating_sub(stake_info.reward_debt[i]))
-                                    / ACC_REWARD_MULTIPLIER;
-                                U128(val.saturating_add(pending))
-                            })
-                            .collect();
-                        results.push(StakeInfoView {
-                            farm_id,
-                            amount: U128(stake_info.amount),
-                            lockup_end_sec: stake_info.lockup_end / 1_000_000_000,
-                            reward_debt: stake_info.reward_debt.iter().map(|v| U128(*v)).collect(),
-                            accrued_rewards: updated_accrued,
-                            reward_tokens: farm.reward_tokens.clone(),
-                        });
+                        results.push(self.stake_info_view(farm_id, &farm, &stake_info));
                         count += 1;
                     }
                 } else {
@@ -0,0 +1,136 @@
+/// Claims rewards straight into `x_token`, auto-staking them for the receiver.
+/// The reward is sent with `ft_transfer_call` and `mint_for:<receiver>`, so the
+/// x_token mints at its current ratio to the receiver instead of this contract.
+/// Whatever the x_token hands back goes to the staker's `accrued_rewards`.
+use crate::*;
+use near_sdk::{GasWeight, PromiseResult};
+
+const GAS_FOR_X_TOKEN_MINT: Gas = Gas::from_tgas(40);
+const GAS_FOR_ON_X_TOKEN_MINT: Gas = Gas::from_tgas(10);
+
+#[near_bindgen]
+impl ChildFarmingContract {
+    /// Claims every reward token of `farm_id` that has an x_token route as xToken.
+    /// The receiver (the beneficiary, or the caller) must be registered with the x_token.
+    /// Rewards without a route stay accrued for `claim_rewards`.
+    #[payable]
+    pub fn claim_rewards_as_x_token(&mut self, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        self.internal_claim_rewards_as_x_token(&env::predecessor_account_id(), farm_id);
+    }
+
+    /// `claim_rewards_as_x_token` on behalf of `account_id`, like
+    /// `claim_rewards_for`. The xToken is still minted for the stake's
+    /// beneficiary (or the staker), never for the operator.
+    #[payable]
+    pub fn claim_rewards_as_x_token_for(&mut self, account_id: AccountId, farm_id: u64) {
+        near_sdk::assert_one_yocto();
+        let stake_info = self
+            .stakes
+            .get(&(account_id.clone(), farm_id))
+            .expect("No stake found");
+        self.assert_owner_or_operator(&stake_info, &account_id);
+        self.internal_claim_rewards_as_x_token(&account_id, farm_id);
+    }
+
+    /// Private callback after the reward was sent to the x_token.
+    #[private]
+    pub fn on_x_token_mint(&mut self, farm_id: u64, index: u32, staker: AccountId, amount: U128) {
+        self.end_claim(farm_id);
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map(|v| v.0.min(amount.0))
+                .unwrap_or(0),
+            _ => 0,
+        };
+        let unused = amount.0 - used;
+        let farm = self.farms.get(&farm_id).expect("Farm not found");
+        self.sub_outstanding(&farm.reward_tokens[index as usize], amount.0);
+        if unused > 0 {
+            self.restore_accrued(&staker, farm_id, index as usize, unused);
+            env::log_str(
+                format!(
+                    "Minting xToken for {} rewards of farm {} failed, restored to {}",
+                    unused, farm_id, staker
+                )
+                .as_str(),
+            );
+        }
+    }
+
+    /// **(Manager or admin)** Lets `reward_token` be claimed into `x_token`,
+    /// or removes the route with `None`. Queued with the minimum notice, like
+    /// `queue_farm_change`; returns the change id.
+    pub fn set_reward_x_token(
+        &mut self,
+        farm_id: u64,
+        reward_token: AccountId,
+        x_token: Option<AccountId>,
+    ) -> u64 {
+        self.queue_farm_change(
+            farm_id,
+            FarmParamChange::RewardXToken { reward_token, x_token },
+            None,
+        )
+    }
+}
+
+impl ChildFarmingContract {
+    fn internal_claim_rewards_as_x_token(&mut self, user: &AccountId, farm_id: u64) {
+        self.update_farm(farm_id);
+
+        let mut farm = self.farms.get(&farm_id).expect("Farm not found");
+        assert!(
+            farm.reward_x_token.iter().any(|t| t.is_some()),
+            "Farm has no x_token route"
+        );
+        let stake_key = (user.clone(), farm_id);
+        let mut stake_info = self.stakes.get(&stake_key).expect("No stake found");
+
+        self.settle_rewards(&mut farm, &mut stake_info);
+        let receiver = stake_info.beneficiary.clone().unwrap_or_else(|| user.clone());
+
+        for i in 0..farm.reward_tokens.len() {
+            let amount = stake_info.accrued_rewards[i];
+            let x_token = match &farm.reward_x_token[i] {
+                Some(x_token) if amount > 0 => x_token.clone(),
+                _ => continue,
+            };
+            stake_info.accrued_rewards[i] = 0;
+            farm.unclaimed_reward[i] = farm.unclaimed_reward[i].saturating_sub(amount);
+            farm.accrued_reward[i] = farm.accrued_reward[i].saturating_sub(amount);
+            self.add_outstanding(&farm.reward_tokens[i], amount);
+            self.start_claim(farm_id);
+            Promise::new(farm.reward_tokens[i].clone())
+                .function_call_weight(
+                    "ft_transfer_call".to_string(),
+                    near_sdk::serde_json::to_vec(&serde_json::json!({
+                        "receiver_id": x_token,
+                        "amount": U128(amount),
+                        "msg": format!("mint_for:{}", receiver),
+                    }))
+                    .unwrap(),
+                    NearToken::from_yoctonear(1),
+                    GAS_FOR_X_TOKEN_MINT,
+                    GasWeight(1),
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_ON_X_TOKEN_MINT)
+                        .with_unused_gas_weight(1)
+                        .on_x_token_mint(farm_id, i as u32, user.clone(), U128(amount)),
+                );
+        }
+
+        self.save_stake(&stake_key, &stake_info);
+        self.save_farm(farm_id, &farm);
+
+        env::log_str(
+            format!(
+                "User {} claimed rewards in farm {} as xToken for {}",
+                user, farm_id, receiver
+            )
+            .as_str(),
+        );
+    }
+}
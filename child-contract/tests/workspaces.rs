@@ -0,0 +1,431 @@
+//! Sandbox tests of the cross-contract flows the unit tests can only mock:
+//! real `ft_transfer_call` stakes and top-ups, claims with real transfers and
+//! registrations, failing transfers, vested and xToken claims, and balance
+//! audits. The farm and `../mock-ft` are built with cargo-near on the first run.
+use near_sdk::json_types::U128;
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::NearToken;
+use near_workspaces::{Account, AccountId, Contract, Worker};
+use serde_json::{json, Value};
+
+type TestResult<T = ()> = Result<T, Box<dyn std::error::Error>>;
+
+struct Env {
+    worker: Worker<Sandbox>,
+    farm: Contract,
+    staking_token: Contract,
+    reward_token: Contract,
+    /// A mock-ft standing in for the locked_token or xToken of `reward_token`.
+    wrapper_token: Contract,
+    owner: Account,
+    alice: Account,
+}
+
+async fn create_account(root: &Account, name: &str) -> TestResult<Account> {
+    Ok(root
+        .create_subaccount(name)
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?)
+}
+
+async fn deploy(root: &Account, name: &str, wasm: &[u8]) -> TestResult<Contract> {
+    let account = create_account(root, name).await?;
+    Ok(account.deploy(wasm).await?.into_result()?)
+}
+
+async fn ft_balance_of(token: &Contract, account_id: &AccountId) -> TestResult<u128> {
+    let balance: U128 = token
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": account_id }))
+        .await?
+        .json()?;
+    Ok(balance.0)
+}
+
+async fn ft_transfer_call(
+    sender: &Account,
+    token: &Contract,
+    receiver_id: &AccountId,
+    amount: u128,
+    msg: &str,
+) -> TestResult {
+    sender
+        .call(token.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": receiver_id,
+            "amount": U128(amount),
+            "msg": msg,
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Advances the sandbox by at least `seconds` of block time.
+async fn fast_forward_sec(worker: &Worker<Sandbox>, seconds: u64) -> TestResult {
+    let until = worker.view_block().await?.timestamp() + seconds * 1_000_000_000;
+    while worker.view_block().await?.timestamp() < until {
+        worker.fast_forward(100).await?;
+    }
+    Ok(())
+}
+
+/// A farm paying 10 reward tokens per second out of a 10_000 pool, with
+/// 1_000 staking tokens minted to alice, who has a storage deposit on the farm.
+async fn setup() -> TestResult<Env> {
+    setup_with_route(None).await
+}
+
+/// Like `setup`, with the reward token routed through `wrapper_token` by
+/// `route`, `reward_vesting` or `reward_x_token`, when given.
+async fn setup_with_route(route: Option<&str>) -> TestResult<Env> {
+    let worker = near_workspaces::sandbox().await?;
+    let farm_wasm = near_workspaces::compile_project("./").await?;
+    let ft_wasm = near_workspaces::compile_project("../mock-ft").await?;
+    let root = worker.root_account()?;
+
+    let owner = create_account(&root, "owner").await?;
+    let alice = create_account(&root, "alice").await?;
+    let farm = deploy(&root, "farm", &farm_wasm).await?;
+    farm.call("new")
+        .args_json(json!({ "admin": owner.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    let staking_token = deploy(&root, "staking", &ft_wasm).await?;
+    let reward_token = deploy(&root, "reward", &ft_wasm).await?;
+    let wrapper_token = deploy(&root, "wrapper", &ft_wasm).await?;
+    for token in [&staking_token, &reward_token, &wrapper_token] {
+        token.call("new").transact().await?.into_result()?;
+    }
+    staking_token
+        .call("mint")
+        .args_json(json!({ "account_id": alice.id(), "amount": U128(1_000) }))
+        .transact()
+        .await?
+        .into_result()?;
+    reward_token
+        .call("mint")
+        .args_json(json!({ "account_id": owner.id(), "amount": U128(1_000_000) }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    for account in [&owner, &alice] {
+        account
+            .call(farm.id(), "storage_deposit")
+            .deposit(NearToken::from_near(1))
+            .transact()
+            .await?
+            .into_result()?;
+    }
+    let mut input = json!({
+        "staking_token": staking_token.id(),
+        "reward_tokens": [reward_token.id()],
+        "lockup_period_sec": 0,
+        "reward_per_session": [U128(10)],
+        "session_interval_sec": 1,
+        "start_at_sec": 0,
+    });
+    if let Some(route) = route {
+        input[route] = json!([wrapper_token.id()]);
+    }
+    // Also registers the farm with both tokens.
+    owner
+        .call(farm.id(), "create_farm")
+        .args_json(json!({ "input": input }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    ft_transfer_call(&owner, &reward_token, farm.id(), 10_000, "ADD_REWARD:0").await?;
+
+    Ok(Env {
+        worker,
+        farm,
+        staking_token,
+        reward_token,
+        wrapper_token,
+        owner,
+        alice,
+    })
+}
+
+#[tokio::test]
+async fn test_stake_and_claim_with_real_transfers() -> TestResult {
+    let env = setup().await?;
+    ft_transfer_call(&env.alice, &env.staking_token, env.farm.id(), 1_000, "STAKE:0").await?;
+    assert_eq!(ft_balance_of(&env.staking_token, env.alice.id()).await?, 0);
+    assert_eq!(ft_balance_of(&env.staking_token, env.farm.id()).await?, 1_000);
+
+    env.worker.fast_forward(20).await?;
+
+    // Alice never registered with the reward token; the claim registers her
+    // from her storage deposit on the farm before paying.
+    env.alice
+        .call(env.farm.id(), "claim_rewards")
+        .args_json(json!({ "farm_id": 0 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let claimed = ft_balance_of(&env.reward_token, env.alice.id()).await?;
+    assert!(claimed > 0);
+
+    let farm: Value = env
+        .farm
+        .view("get_farm")
+        .args_json(json!({ "farm_id": 0 }))
+        .await?
+        .json()?;
+    assert_eq!(farm["total_staked"], "1000");
+    assert!(env
+        .farm
+        .view("get_pending_payouts")
+        .args_json(json!({ "account_id": env.alice.id() }))
+        .await?
+        .json::<Vec<Value>>()?
+        .is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_withdraw_is_owed_until_retried() -> TestResult {
+    let env = setup().await?;
+    ft_transfer_call(&env.alice, &env.staking_token, env.farm.id(), 1_000, "STAKE:0").await?;
+
+    // With no balance left, alice can drop her registration, so the transfer back fails.
+    env.alice
+        .call(env.staking_token.id(), "storage_unregister")
+        .args_json(json!({ "force": true }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+    env.alice
+        .call(env.farm.id(), "withdraw")
+        .args_json(json!({ "farm_id": 0, "amount": U128(1_000) }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let owed: Vec<Value> = env
+        .farm
+        .view("get_pending_payouts")
+        .args_json(json!({ "account_id": env.alice.id() }))
+        .await?
+        .json()?;
+    assert_eq!(owed, vec![json!({ "token_id": env.staking_token.id(), "amount": "1000" })]);
+    assert_eq!(ft_balance_of(&env.staking_token, env.farm.id()).await?, 1_000);
+
+    env.alice
+        .call(env.staking_token.id(), "storage_deposit")
+        .args_json(json!({}))
+        .deposit(NearToken::from_millinear(10))
+        .transact()
+        .await?
+        .into_result()?;
+    env.alice
+        .call(env.farm.id(), "retry_payout")
+        .args_json(json!({ "token": env.staking_token.id() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(ft_balance_of(&env.staking_token, env.alice.id()).await?, 1_000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_audit_and_recover_surplus() -> TestResult {
+    let env = setup().await?;
+    ft_transfer_call(&env.alice, &env.staking_token, env.farm.id(), 1_000, "STAKE:0").await?;
+
+    let report: Value = env
+        .owner
+        .call(env.farm.id(), "audit_solvency")
+        .args_json(json!({ "token": env.reward_token.id() }))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(report["deficit"], "0");
+    assert_eq!(report["surplus"], "0");
+
+    // A plain ft_transfer bypasses ft_on_transfer and is only recoverable as surplus.
+    env.owner
+        .call(env.reward_token.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": env.farm.id(), "amount": U128(50) }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+    let before = ft_balance_of(&env.reward_token, env.owner.id()).await?;
+    let recovered: U128 = env
+        .owner
+        .call(env.farm.id(), "recover_surplus")
+        .args_json(json!({
+            "token": env.reward_token.id(),
+            "receiver": env.owner.id(),
+            "amount": U128(50),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(recovered.0, 50);
+    assert_eq!(ft_balance_of(&env.reward_token, env.owner.id()).await?, before + 50);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vested_claim_through_locked_token() -> TestResult {
+    let env = setup_with_route(Some("reward_vesting")).await?;
+    env.alice
+        .call(env.wrapper_token.id(), "storage_deposit")
+        .args_json(json!({}))
+        .deposit(NearToken::from_millinear(10))
+        .transact()
+        .await?
+        .into_result()?;
+    ft_transfer_call(&env.alice, &env.staking_token, env.farm.id(), 1_000, "STAKE:0").await?;
+
+    env.worker.fast_forward(20).await?;
+
+    env.alice
+        .call(env.farm.id(), "claim_rewards")
+        .args_json(json!({ "farm_id": 0 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // The reward was minted into locked tokens, which went on to alice.
+    let locked = ft_balance_of(&env.wrapper_token, env.alice.id()).await?;
+    assert!(locked > 0);
+    assert_eq!(ft_balance_of(&env.reward_token, env.wrapper_token.id()).await?, locked);
+    assert_eq!(ft_balance_of(&env.reward_token, env.alice.id()).await?, 0);
+    assert_eq!(ft_balance_of(&env.wrapper_token, env.farm.id()).await?, 0);
+    assert!(env
+        .farm
+        .view("get_pending_payouts")
+        .args_json(json!({ "account_id": env.alice.id() }))
+        .await?
+        .json::<Vec<Value>>()?
+        .is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_claim_as_x_token_mints_for_staker() -> TestResult {
+    let env = setup_with_route(Some("reward_x_token")).await?;
+    ft_transfer_call(&env.alice, &env.staking_token, env.farm.id(), 1_000, "STAKE:0").await?;
+
+    env.worker.fast_forward(20).await?;
+
+    env.alice
+        .call(env.farm.id(), "claim_rewards_as_x_token")
+        .args_json(json!({ "farm_id": 0 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // `mint_for:alice` minted straight to alice, never to the farm.
+    let minted = ft_balance_of(&env.wrapper_token, env.alice.id()).await?;
+    assert!(minted > 0);
+    assert_eq!(ft_balance_of(&env.reward_token, env.wrapper_token.id()).await?, minted);
+    assert_eq!(ft_balance_of(&env.wrapper_token, env.farm.id()).await?, 0);
+    assert_eq!(ft_balance_of(&env.reward_token, env.alice.id()).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_close_farm_waits_for_claim_callback() -> TestResult {
+    let env = setup_with_route(Some("reward_vesting")).await?;
+    env.alice
+        .call(env.wrapper_token.id(), "storage_deposit")
+        .args_json(json!({}))
+        .deposit(NearToken::from_millinear(10))
+        .transact()
+        .await?
+        .into_result()?;
+    ft_transfer_call(&env.alice, &env.staking_token, env.farm.id(), 1_000, "STAKE:0").await?;
+
+    // Let the whole pool be emitted, then leave with the principal.
+    fast_forward_sec(&env.worker, 2_000).await?;
+    env.alice
+        .call(env.farm.id(), "withdraw")
+        .args_json(json!({ "farm_id": 0, "amount": U128(1_000) }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // Hold the mint for a while, so the claim's callback is still pending.
+    env.wrapper_token
+        .call("set_mint_delay")
+        .args_json(json!({ "blocks": 10 }))
+        .transact()
+        .await?
+        .into_result()?;
+    let claim = env
+        .alice
+        .call(env.farm.id(), "claim_rewards")
+        .args_json(json!({ "farm_id": 0 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact_async()
+        .await?;
+    // Wait for the reward to reach the locked_token, which has not resolved yet.
+    let mut polls = 0;
+    while ft_balance_of(&env.reward_token, env.wrapper_token.id()).await? == 0 {
+        assert!(polls < 100, "The claim never reached the locked_token");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        polls += 1;
+    }
+
+    let result = env
+        .owner
+        .call(env.farm.id(), "close_farm")
+        .args_json(json!({ "farm_id": 0 }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result).contains("Farm still has claims in flight"));
+
+    claim.await?.into_result()?;
+    let locked = ft_balance_of(&env.wrapper_token, env.alice.id()).await?;
+    assert!(locked > 0);
+    assert_eq!(ft_balance_of(&env.reward_token, env.wrapper_token.id()).await?, locked);
+
+    env.owner
+        .call(env.farm.id(), "close_farm")
+        .args_json(json!({ "farm_id": 0 }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let farm: Option<Value> = env
+        .farm
+        .view("get_farm")
+        .args_json(json!({ "farm_id": 0 }))
+        .await?
+        .json()?;
+    assert!(farm.is_none());
+    Ok(())
+}
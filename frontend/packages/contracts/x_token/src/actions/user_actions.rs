@@ -1,4 +1,4 @@
-use crate::errors::{ERR_001, ERR_002, ERR_003};
+use crate::errors::{ERR_001, ERR_002, ERR_003, ERR_004};
 use crate::ext_interface::{ext_self, ext_token_contract};
 use crate::*;
 
@@ -26,7 +26,15 @@ impl Contract {
         self.internal_deposit_jump_profits(amount.0);
         U128(0)
       }
-      _ => panic!("{}", ERR_001),
+      // mints for a beneficiary other than the sender, e.g. rewards claimed by a farm
+      msg => match msg.strip_prefix("mint_for:") {
+        Some(beneficiary) => {
+          let beneficiary: AccountId = beneficiary.parse().expect(ERR_004);
+          self.internal_mint_x_token(amount.0, beneficiary);
+          U128(0)
+        }
+        None => panic!("{}", ERR_001),
+      },
     }
   }
 
@@ -228,4 +236,50 @@ mod tests {
       )
     );
   }
+
+  #[test]
+  fn test_ft_on_transfer_mint_for() {
+    let context = get_context(vec![], 0, 0, BASE_TOKEN_ADDRESS.clone().parse().unwrap());
+    testing_env!(context);
+
+    let initial_amount_of_xtoken: u128 = 10;
+    let initial_amount_of_jump: u128 = 10;
+    let quantity_deposited: u128 = 10;
+
+    let mut contract = init_contract(initial_amount_of_jump.clone());
+
+    contract
+      .ft_functionality
+      .internal_register_account(&SIGNER_ACCOUNT.clone().parse().unwrap());
+    contract
+      .ft_functionality
+      .internal_register_account(&OWNER_ACCOUNT.clone().parse().unwrap());
+
+    contract.ft_functionality.internal_deposit(
+      &SIGNER_ACCOUNT.parse().unwrap(),
+      initial_amount_of_xtoken.clone(),
+    );
+
+    let msg = format!("mint_for:{}", OWNER_ACCOUNT);
+
+    contract.ft_on_transfer(
+      SIGNER_ACCOUNT.parse().unwrap(),
+      U128(quantity_deposited),
+      msg,
+    );
+
+    // the beneficiary gets the xToken, the sender's balance is untouched
+    assert_eq!(
+      contract
+        .ft_functionality
+        .ft_balance_of(OWNER_ACCOUNT.parse().unwrap()),
+      U128(quantity_deposited * initial_amount_of_xtoken / initial_amount_of_jump)
+    );
+    assert_eq!(
+      contract
+        .ft_functionality
+        .ft_balance_of(SIGNER_ACCOUNT.parse().unwrap()),
+      U128(initial_amount_of_xtoken)
+    );
+  }
 }
@@ -6,3 +6,5 @@ pub(crate) const ERR_002: &str = "ft_on_transfer: only accepts tokens from self.
 pub(crate) const ERR_003: &str =
   "user_actions: burn_x_token: Not enough gas attached to complete the transactions, 
 you must attach at least 3 * 50_000_000_000_000 gas to complete your request";
+
+pub(crate) const ERR_004: &str = "ft_on_transfer: mint_for: invalid beneficiary account id";
@@ -1,9 +1,16 @@
+// `call_create_farm` mirrors the child's farm fields; near_bindgen repeats its
+// signature in generated code, so the lint is allowed crate-wide.
+#![allow(clippy::too_many_arguments)]
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize}, collections::UnorderedMap, env, ext_contract, near_bindgen, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseResult
 };
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
+use farm_roles::{Ownable, Role, Roles};
 
+/// Gas for the child's `create_farm`, which also registers the child with
+/// each of the farm's tokens.
+const GAS_FOR_CREATE_FARM: Gas = Gas::from_tgas(100);
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(crate = "near_sdk::serde")]
@@ -13,7 +20,8 @@ pub enum ChildContractType {
     LP,
 }
 
-#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct ChildContractMetadata {
     pub contract_type: ChildContractType,
     pub deployed_at: u64,
@@ -22,6 +30,13 @@ pub struct ChildContractMetadata {
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct MasterContract {
+    child_contracts: UnorderedMap<AccountId, ChildContractMetadata>,
+    roles: Roles,
+}
+
+/// Contract state before roles, when a single `owner` held every permission.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldMasterContract {
     child_contracts: UnorderedMap<AccountId, ChildContractMetadata>,
     owner: AccountId,
 }
@@ -32,14 +47,33 @@ impl MasterContract {
     pub fn new(owner: AccountId) -> Self {
         Self {
             child_contracts: UnorderedMap::new(b"childs".to_vec()),
-            owner,
+            roles: Roles::new(owner, b"roles".to_vec()),
+        }
+    }
+
+    /// Converts the state from before roles. The registered children are kept
+    /// and the old owner becomes the roles owner, which holds every role.
+    /// Deploy this code and call `migrate` in one batch transaction.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let OldMasterContract { child_contracts, owner } =
+            env::state_read().expect("No state to migrate");
+        env::log_str(format!("Migrated owner {} to roles", owner).as_str());
+        Self {
+            child_contracts,
+            roles: Roles::new(owner, b"roles".to_vec()),
         }
     }
 
-    /// **(Owner-only)** Register an already-deployed child contract.
+    /// **(FarmManager role)** Register an already-deployed child contract.
     /// This is useful if the child contract was deployed externally.
     pub fn register_child_contract(&mut self, child_account_id: AccountId, contract_type: ChildContractType) {
-        self.assert_owner();
+        self.roles.assert_role(Role::FarmManager);
+        self.internal_register_child_contract(child_account_id, contract_type);
+    }
+
+    fn internal_register_child_contract(&mut self, child_account_id: AccountId, contract_type: ChildContractType) {
         let metadata = ChildContractMetadata {
             contract_type: contract_type.clone(),
             deployed_at: env::block_timestamp(),
@@ -54,9 +88,9 @@ impl MasterContract {
         );
     }
 
-    /// **(Owner-only)** Unregister a child contract.
+    /// **(FarmManager role)** Unregister a child contract.
     pub fn unregister_child_contract(&mut self, child_account_id: AccountId) {
-        self.assert_owner();
+        self.roles.assert_role(Role::FarmManager);
         self.child_contracts.remove(&child_account_id);
         env::log_str(format!("Unregistered child contract: {}", child_account_id).as_str());
     }
@@ -66,7 +100,7 @@ impl MasterContract {
         self.child_contracts.iter().collect()
     }
 
-    /// A generic method to call a function on a child contract.
+    /// **(FarmManager role)** A generic method to call a function on a child contract.
     /// You must supply:
     /// - the child contract account id,
     /// - the method name,
@@ -81,6 +115,7 @@ impl MasterContract {
         deposit: NearToken,
         gas: Gas,
     ) -> Promise {
+        self.roles.assert_role(Role::FarmManager);
         Promise::new(child_account_id).function_call(
             method_name,
             args,
@@ -89,6 +124,7 @@ impl MasterContract {
         )
     }
 
+    /// **(FarmManager role)** Creates a farm on a child contract, managed by this contract.
     pub fn call_create_farm(
         &self,
         child_account_id: AccountId,
@@ -100,29 +136,28 @@ impl MasterContract {
         start_at_sec: u64,
         attached_deposit: NearToken,
     ) -> Promise {
-        // Build the JSON arguments expected by the child contract's create_farm.
-        let args = near_sdk::serde_json::json!({
+        self.roles.assert_role(Role::FarmManager);
+        // Build the JSON arguments expected by the child contract's create_farm,
+        // which takes a single `input` object.
+        let args = near_sdk::serde_json::json!({ "input": {
             "staking_token": staking_token,
             "reward_tokens": reward_tokens,
             "lockup_period_sec": lockup_period_sec,
             "reward_per_session": reward_per_session,
             "session_interval_sec": session_interval_sec,
             "start_at_sec": start_at_sec,
-        });
+        }});
         let args_vec = near_sdk::serde_json::to_vec(&args).unwrap();
 
-        // Specify the gas to be attached; you can adjust this value as needed.
-        let gas_amount = Gas::from_tgas(50);
-
         Promise::new(child_account_id).function_call(
             "create_farm".to_string(),
             args_vec,
             attached_deposit,
-            gas_amount,
+            GAS_FOR_CREATE_FARM,
         )
     }
 
-    /// **(Owner-only) [Advanced Option]**
+    /// **(Upgrader role) [Advanced Option]**
     /// Deploy a new child contract to a sub-account and register it.
     ///
     /// In this example you provide:
@@ -141,7 +176,7 @@ impl MasterContract {
         child_contract_wasm: Vec<u8>,
         initial_balance: NearToken,
     ) -> Promise {
-        self.assert_owner();
+        self.roles.assert_role(Role::Upgrader);
         // Create a new account for the child contract, fund it, and deploy the given WASM code.
         Promise::new(child_account_id.clone())
             .create_account()
@@ -165,7 +200,7 @@ impl MasterContract {
         );
         match env::promise_result(0) {
             PromiseResult::Successful(_) => {
-                self.register_child_contract(child_account_id, contract_type);
+                self.internal_register_child_contract(child_account_id, contract_type);
                 env::log_str("Child contract deployed and registered.");
             }
             _ => {
@@ -174,13 +209,36 @@ impl MasterContract {
         }
     }
 
-    /// Simple helper: only the owner may call certain methods.
-    fn assert_owner(&self) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner,
-            "Only owner can call this method"
-        );
+    /// **(Owner-only)** Gives `role` to `account_id`.
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        self.roles.grant_role(role, account_id);
+    }
+
+    /// **(Owner-only)** Takes `role` away from `account_id`.
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        self.roles.revoke_role(role, account_id);
+    }
+
+    /// The owner holds every role.
+    pub fn has_role(&self, role: Role, account_id: AccountId) -> bool {
+        self.roles.has_role(role, &account_id)
+    }
+
+    /// **(Owner-only)** Starts an ownership transfer; `new_owner` must call `accept_owner`.
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
+        self.roles.set_owner(new_owner);
+    }
+
+    pub fn accept_owner(&mut self) {
+        self.roles.accept_owner();
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.roles.get_owner()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.roles.get_pending_owner()
     }
 }
 
@@ -230,13 +288,13 @@ mod tests {
 
         let list = contract.list_child_contracts();
         assert_eq!(list.len(), 1, "Expected one child contract registered");
-        let (registered_account, metadata) = list.get(0).unwrap();
+        let (registered_account, metadata) = list.first().unwrap();
         assert_eq!(registered_account, &child_account);
         assert_eq!(metadata.contract_type, ChildContractType::Farming);
     }
 
     #[test]
-    #[should_panic(expected = "Only owner can call this method")]
+    #[should_panic(expected = "Caller does not have the FarmManager role")]
     fn test_register_child_contract_non_owner() {
         let owner = accounts(0);
         let non_owner = accounts(1);
@@ -263,7 +321,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Only owner can call this method")]
+    #[should_panic(expected = "Caller does not have the FarmManager role")]
     fn test_unregister_child_contract_non_owner() {
         let owner = accounts(0);
         let non_owner = accounts(1);
@@ -326,7 +384,91 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Only owner can call this method")]
+    fn test_call_create_farm_args_and_gas() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let contract = MasterContract::new(owner);
+
+        let child_account: AccountId = "child1.testnet".parse().unwrap();
+        contract.call_create_farm(
+            child_account.clone(),
+            "staking.token".parse().unwrap(),
+            vec!["reward.token".parse().unwrap()],
+            60,
+            vec![U128(100)],
+            10,
+            0,
+            NearToken::from_yoctonear(5),
+        );
+
+        // The child's create_farm takes a single `input` object.
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, child_account);
+        match &receipts[0].actions[0] {
+            near_sdk::mock::MockAction::FunctionCallWeight {
+                method_name,
+                args,
+                attached_deposit,
+                prepaid_gas,
+                ..
+            } => {
+                assert_eq!(method_name, b"create_farm");
+                let args: near_sdk::serde_json::Value = near_sdk::serde_json::from_slice(args).unwrap();
+                assert_eq!(
+                    args,
+                    near_sdk::serde_json::json!({ "input": {
+                        "staking_token": "staking.token",
+                        "reward_tokens": ["reward.token"],
+                        "lockup_period_sec": 60,
+                        "reward_per_session": ["100"],
+                        "session_interval_sec": 10,
+                        "start_at_sec": 0,
+                    }})
+                );
+                assert_eq!(*attached_deposit, NearToken::from_yoctonear(5));
+                assert_eq!(*prepaid_gas, Gas::from_tgas(100));
+            }
+            action => panic!("Unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Caller does not have the FarmManager role")]
+    fn test_call_child_contract_non_manager() {
+        let context = get_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let contract = MasterContract::new(accounts(0));
+        contract.call_child_contract(
+            "child1.testnet".parse().unwrap(),
+            "withdraw".to_string(),
+            vec![],
+            NearToken::from_yoctonear(0),
+            Gas::from_tgas(10),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Caller does not have the FarmManager role")]
+    fn test_call_create_farm_non_manager() {
+        let context = get_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let contract = MasterContract::new(accounts(0));
+        contract.call_create_farm(
+            "child1.testnet".parse().unwrap(),
+            "staking.token".parse().unwrap(),
+            vec!["reward.token".parse().unwrap()],
+            60,
+            vec![U128(100)],
+            10,
+            0,
+            NearToken::from_yoctonear(0),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Caller does not have the Upgrader role")]
     fn test_deploy_child_contract_non_owner() {
         let owner = accounts(0);
         let non_owner = accounts(1);
@@ -339,4 +481,57 @@ mod tests {
         let initial_balance = NearToken::from_yoctonear(10);
         contract.deploy_child_contract(child_account, ChildContractType::Farming, wasm_code, initial_balance);
     }
+
+    #[test]
+    fn test_granted_farm_manager_can_register() {
+        let owner = accounts(0);
+        let manager = accounts(1);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let mut contract = MasterContract::new(owner);
+        contract.grant_role(Role::FarmManager, manager.clone());
+        assert!(contract.has_role(Role::FarmManager, manager.clone()));
+        assert!(!contract.has_role(Role::Upgrader, manager.clone()));
+
+        let context = get_context(manager, NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let child_account: AccountId = "child1.testnet".parse().unwrap();
+        contract.register_child_contract(child_account, ChildContractType::Farming);
+        assert_eq!(contract.list_child_contracts().len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_from_owner_layout() {
+        let owner = accounts(0);
+        let context = get_context(owner.clone(), NearToken::from_yoctonear(0), 0);
+        testing_env!(context.build());
+        let child_account: AccountId = "child1.testnet".parse().unwrap();
+        let mut child_contracts = UnorderedMap::new(b"childs".to_vec());
+        child_contracts.insert(
+            &child_account,
+            &ChildContractMetadata {
+                contract_type: ChildContractType::Farming,
+                deployed_at: 7,
+            },
+        );
+        env::state_write(&OldMasterContract {
+            child_contracts,
+            owner: owner.clone(),
+        });
+
+        let mut contract = MasterContract::migrate();
+        assert_eq!(contract.get_owner(), owner);
+        assert!(contract.has_role(Role::FarmManager, owner.clone()));
+        assert!(contract.has_role(Role::Upgrader, owner.clone()));
+        let list = contract.list_child_contracts();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0, child_account);
+        assert_eq!(list[0].1.deployed_at, 7);
+
+        // The old owner can still manage children and hand out roles.
+        contract.unregister_child_contract(child_account);
+        contract.grant_role(Role::FarmManager, accounts(1));
+        assert!(contract.has_role(Role::FarmManager, accounts(1)));
+        assert_eq!(contract.list_child_contracts().len(), 0);
+    }
 }
@@ -0,0 +1,178 @@
+//! Sandbox tests of the master contract driving a real child farm: deploying
+//! it, calling into it, and creating a farm through `call_create_farm`.
+//! `../child-contract` and `../mock-ft` are built with cargo-near on the first run.
+use near_sdk::json_types::U128;
+use near_workspaces::types::{Gas, NearToken};
+use near_workspaces::{Account, AccountId, Contract};
+use serde_json::{json, Value};
+
+type TestResult<T = ()> = Result<T, Box<dyn std::error::Error>>;
+
+async fn create_account(root: &Account, name: &str) -> TestResult<Account> {
+    Ok(root
+        .create_subaccount(name)
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?)
+}
+
+async fn deploy(root: &Account, name: &str, wasm: &[u8]) -> TestResult<Contract> {
+    let account = create_account(root, name).await?;
+    Ok(account.deploy(wasm).await?.into_result()?)
+}
+
+/// Has the master call `method` on `child` with JSON `args`.
+async fn call_child(
+    owner: &Account,
+    master: &Contract,
+    child: &AccountId,
+    method: &str,
+    args: Value,
+    deposit: NearToken,
+) -> TestResult {
+    owner
+        .call(master.id(), "call_child_contract")
+        .args_json(json!({
+            "child_account_id": child,
+            "method_name": method,
+            "args": serde_json::to_vec(&args)?,
+            "deposit": deposit,
+            "gas": Gas::from_tgas(50),
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deploy_child_and_create_farm() -> TestResult {
+    let worker = near_workspaces::sandbox().await?;
+    let master_wasm = near_workspaces::compile_project("./").await?;
+    let child_wasm = near_workspaces::compile_project("../child-contract").await?;
+    let ft_wasm = near_workspaces::compile_project("../mock-ft").await?;
+    let root = worker.root_account()?;
+
+    let owner = create_account(&root, "owner").await?;
+    let master = deploy(&root, "master", &master_wasm).await?;
+    master
+        .call("new")
+        .args_json(json!({ "owner": owner.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    let staking_token = deploy(&root, "staking", &ft_wasm).await?;
+    let reward_token = deploy(&root, "reward", &ft_wasm).await?;
+    for token in [&staking_token, &reward_token] {
+        token.call("new").transact().await?.into_result()?;
+    }
+
+    // Deploy a child to a sub-account of the master; the callback registers it.
+    let child_id: AccountId = format!("farm.{}", master.id()).parse()?;
+    owner
+        .call(master.id(), "deploy_child_contract")
+        .args_json(json!({
+            "child_account_id": child_id,
+            "contract_type": "Farming",
+            "child_contract_wasm": child_wasm,
+            "initial_balance": NearToken::from_near(10),
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let children: Vec<(AccountId, Value)> = master.view("list_child_contracts").await?.json()?;
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].0, child_id);
+    assert_eq!(children[0].1["contract_type"], "Farming");
+
+    // Initialize the child and give the master a storage deposit on it.
+    call_child(
+        &owner,
+        &master,
+        &child_id,
+        "new",
+        json!({ "admin": owner.id() }),
+        NearToken::from_near(0),
+    )
+    .await?;
+    call_child(
+        &owner,
+        &master,
+        &child_id,
+        "storage_deposit",
+        json!({}),
+        NearToken::from_near(1),
+    )
+    .await?;
+
+    owner
+        .call(master.id(), "call_create_farm")
+        .args_json(json!({
+            "child_account_id": child_id,
+            "staking_token": staking_token.id(),
+            "reward_tokens": [reward_token.id()],
+            "lockup_period_sec": 60,
+            "reward_per_session": [U128(100)],
+            "session_interval_sec": 10,
+            "start_at_sec": 0,
+            "attached_deposit": NearToken::from_near(0),
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let farms: Vec<Value> = worker
+        .view(&child_id, "list_farms")
+        .args_json(json!({ "from_index": 0, "limit": 10 }))
+        .await?
+        .json()?;
+    assert_eq!(farms.len(), 1);
+    assert_eq!(farms[0]["manager"], json!(master.id()));
+    assert_eq!(farms[0]["staking_token"], json!(staking_token.id()));
+
+    // The child registered itself with both tokens while creating the farm.
+    for token in [&staking_token, &reward_token] {
+        let registration: Value = token
+            .view("storage_balance_of")
+            .args_json(json!({ "account_id": child_id }))
+            .await?
+            .json()?;
+        assert!(!registration.is_null());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deploy_child_requires_upgrader() -> TestResult {
+    let worker = near_workspaces::sandbox().await?;
+    let master_wasm = near_workspaces::compile_project("./").await?;
+    let root = worker.root_account()?;
+    let owner = create_account(&root, "owner").await?;
+    let mallory = create_account(&root, "mallory").await?;
+    let master = deploy(&root, "master", &master_wasm).await?;
+    master
+        .call("new")
+        .args_json(json!({ "owner": owner.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let result = mallory
+        .call(master.id(), "deploy_child_contract")
+        .args_json(json!({
+            "child_account_id": format!("farm.{}", master.id()),
+            "contract_type": "Farming",
+            "child_contract_wasm": Vec::<u8>::new(),
+            "initial_balance": NearToken::from_near(1),
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result).contains("Caller does not have the Upgrader role"));
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Minimal NEP-141/NEP-145 token for the sandbox integration tests of
+//! `child-contract` and `master-contract`. Anyone can `mint`; not for deployment.
+//!
+//! It also stands in for a locked_token or xToken: tokens sent to it with
+//! `ft_transfer_call` and a `{"type": "Mint", "account_id"}` or
+//! `mint_for:<account>` msg are kept, and as many of its own are minted to
+//! that account. Any other msg is refunded.
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::fungible_token::{
+    FungibleToken, FungibleTokenCore, FungibleTokenResolver,
+};
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::Deserialize;
+use near_sdk::{env, near_bindgen, AccountId, NearToken, PanicOnDefault, PromiseOrValue};
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct MockFungibleToken {
+    token: FungibleToken,
+    /// Blocks a mint from `ft_on_transfer` waits before it resolves.
+    mint_delay: u32,
+}
+
+/// The locked_token's mint message.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "type")]
+enum LockedTokenMsg {
+    Mint { account_id: AccountId },
+}
+
+#[near_bindgen]
+impl MockFungibleToken {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            token: FungibleToken::new(b"t".to_vec()),
+            mint_delay: 0,
+        }
+    }
+
+    /// Makes later mints from `ft_on_transfer` resolve `blocks` blocks late,
+    /// so tests can act while the sender still waits for its callback.
+    pub fn set_mint_delay(&mut self, blocks: u32) {
+        self.mint_delay = blocks;
+    }
+
+    /// Mints `amount` to `account_id` after `blocks` more blocks and resolves
+    /// to 0, the amount `ft_on_transfer` hands back.
+    #[private]
+    pub fn mint_after(&mut self, blocks: u32, account_id: AccountId, amount: U128) -> PromiseOrValue<U128> {
+        if blocks == 0 {
+            self.mint(account_id, amount);
+            return PromiseOrValue::Value(U128(0));
+        }
+        PromiseOrValue::Promise(
+            Self::ext(env::current_account_id())
+                .with_unused_gas_weight(1)
+                .mint_after(blocks - 1, account_id, amount),
+        )
+    }
+
+    /// Mints `amount` to `account_id`, registering it first if needed.
+    pub fn mint(&mut self, account_id: AccountId, amount: U128) {
+        if !self.token.accounts.contains_key(&account_id) {
+            self.token.internal_register_account(&account_id);
+        }
+        self.token.internal_deposit(&account_id, amount.0);
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for MockFungibleToken {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.token.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for MockFungibleToken {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let account_id = match msg.strip_prefix("mint_for:") {
+            Some(account_id) => account_id.parse().ok(),
+            None => near_sdk::serde_json::from_str::<LockedTokenMsg>(&msg)
+                .ok()
+                .map(|LockedTokenMsg::Mint { account_id }| account_id),
+        };
+        match account_id {
+            Some(account_id) => {
+                env::log_str(&format!("Minting {} to {} for {}", amount.0, account_id, sender_id));
+                self.mint_after(self.mint_delay, account_id, amount)
+            }
+            None => PromiseOrValue::Value(amount),
+        }
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for MockFungibleToken {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, _burned_amount) =
+            self.token.internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        used_amount.into()
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for MockFungibleToken {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        self.token.storage_deposit(account_id, registration_only)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        self.token.storage_withdraw(amount)
+    }
+
+    /// Lets tests make transfers to an account fail again.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        self.token.internal_storage_unregister(force).is_some()
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
+    }
+}
@@ -0,0 +1,237 @@
+//! Role-based access control shared by the farm contracts.
+//!
+//! `Ownable` keeps the shape of the trait in `modified_contract_standards`
+//! (`assert_owner`, `get_owner`, `set_owner`). That crate targets near-sdk 3.1,
+//! so it can't be linked into these near-sdk 5 contracts; the trait is mirrored
+//! here instead. Ownership moves in two steps: `set_owner` only proposes the
+//! new owner, who has to call `accept_owner`.
+//!
+//! The owner implicitly holds every role. Changes are logged as NEP-297 events
+//! with the `farm-roles` standard.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, AccountId};
+
+pub const EVENT_STANDARD: &str = "farm-roles";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    /// Held by the single owner only; moved with `set_owner`/`accept_owner`.
+    Owner,
+    /// May pause farms.
+    Pauser,
+    /// May manage any farm as if it were its manager.
+    FarmManager,
+    /// Reserved for fee configuration.
+    FeeManager,
+    /// May deploy and upgrade contract code.
+    Upgrader,
+}
+
+pub trait Ownable {
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.get_owner(),
+            "Only owner can call this method"
+        );
+    }
+    fn get_owner(&self) -> AccountId;
+    fn set_owner(&mut self, owner: AccountId);
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Roles {
+    owner: AccountId,
+    pending_owner: Option<AccountId>,
+    members: LookupMap<AccountId, Vec<Role>>,
+}
+
+fn emit_event(event: &str, data: near_sdk::serde_json::Value) {
+    env::log_str(&format!(
+        "EVENT_JSON:{}",
+        near_sdk::serde_json::json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_VERSION,
+            "event": event,
+            "data": [data],
+        })
+    ));
+}
+
+impl Roles {
+    /// `prefix` must be unique among the contract's collections.
+    pub fn new(owner: AccountId, prefix: Vec<u8>) -> Self {
+        Self {
+            owner,
+            pending_owner: None,
+            members: LookupMap::new(prefix),
+        }
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    pub fn has_role(&self, role: Role, account_id: &AccountId) -> bool {
+        account_id == &self.owner
+            || self
+                .members
+                .get(account_id)
+                .is_some_and(|roles| roles.contains(&role))
+    }
+
+    /// Panics unless the caller holds `role`.
+    pub fn assert_role(&self, role: Role) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            self.has_role(role, &caller),
+            "Caller does not have the {:?} role",
+            role
+        );
+    }
+
+    /// **(Owner-only)** Gives `role` to `account_id`.
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        self.assert_owner();
+        assert!(role != Role::Owner, "Ownership is transferred with set_owner");
+        let mut roles = self.members.get(&account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.members.insert(&account_id, &roles);
+        }
+        emit_event(
+            "role_granted",
+            near_sdk::serde_json::json!({
+                "role": role,
+                "account_id": account_id,
+                "sender_id": env::predecessor_account_id(),
+            }),
+        );
+    }
+
+    /// **(Owner-only)** Takes `role` away from `account_id`.
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        self.assert_owner();
+        let mut roles = self.members.get(&account_id).unwrap_or_default();
+        roles.retain(|r| r != &role);
+        if roles.is_empty() {
+            self.members.remove(&account_id);
+        } else {
+            self.members.insert(&account_id, &roles);
+        }
+        emit_event(
+            "role_revoked",
+            near_sdk::serde_json::json!({
+                "role": role,
+                "account_id": account_id,
+                "sender_id": env::predecessor_account_id(),
+            }),
+        );
+    }
+
+    /// Completes a transfer started with `set_owner`; only the proposed owner may call it.
+    pub fn accept_owner(&mut self) {
+        let caller = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_owner.as_ref(),
+            Some(&caller),
+            "Only the proposed owner can accept ownership"
+        );
+        let previous = std::mem::replace(&mut self.owner, caller);
+        self.pending_owner = None;
+        emit_event(
+            "ownership_transferred",
+            near_sdk::serde_json::json!({
+                "previous_owner": previous,
+                "new_owner": self.owner,
+            }),
+        );
+    }
+}
+
+impl Ownable for Roles {
+    fn get_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    /// Proposes `owner`; nothing changes until they call `accept_owner`.
+    fn set_owner(&mut self, owner: AccountId) {
+        self.assert_owner();
+        emit_event(
+            "ownership_transfer_started",
+            near_sdk::serde_json::json!({
+                "owner": self.owner,
+                "pending_owner": owner,
+            }),
+        );
+        self.pending_owner = Some(owner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn set_caller(account_id: AccountId) {
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(account_id);
+        testing_env!(builder.build());
+    }
+
+    #[test]
+    fn test_grant_and_revoke_role() {
+        set_caller(accounts(0));
+        let mut roles = Roles::new(accounts(0), b"roles".to_vec());
+        assert!(roles.has_role(Role::Pauser, &accounts(0)));
+        assert!(!roles.has_role(Role::Pauser, &accounts(1)));
+
+        roles.grant_role(Role::Pauser, accounts(1));
+        assert!(roles.has_role(Role::Pauser, &accounts(1)));
+        assert!(!roles.has_role(Role::Upgrader, &accounts(1)));
+        assert!(get_logs()[0].starts_with("EVENT_JSON:"));
+        assert!(get_logs()[0].contains("\"event\":\"role_granted\""));
+
+        roles.revoke_role(Role::Pauser, accounts(1));
+        assert!(!roles.has_role(Role::Pauser, &accounts(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only owner can call this method")]
+    fn test_grant_role_non_owner() {
+        set_caller(accounts(1));
+        let mut roles = Roles::new(accounts(0), b"roles".to_vec());
+        roles.grant_role(Role::Pauser, accounts(1));
+    }
+
+    #[test]
+    fn test_two_step_ownership_transfer() {
+        set_caller(accounts(0));
+        let mut roles = Roles::new(accounts(0), b"roles".to_vec());
+        roles.set_owner(accounts(1));
+        // Nothing changes until the new owner accepts.
+        assert_eq!(roles.get_owner(), accounts(0));
+        assert_eq!(roles.get_pending_owner(), Some(accounts(1)));
+
+        set_caller(accounts(1));
+        roles.accept_owner();
+        assert_eq!(roles.get_owner(), accounts(1));
+        assert_eq!(roles.get_pending_owner(), None);
+        assert!(!roles.has_role(Role::FarmManager, &accounts(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the proposed owner can accept ownership")]
+    fn test_accept_owner_wrong_account() {
+        set_caller(accounts(0));
+        let mut roles = Roles::new(accounts(0), b"roles".to_vec());
+        roles.set_owner(accounts(1));
+        set_caller(accounts(2));
+        roles.accept_owner();
+    }
+}